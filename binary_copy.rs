@@ -0,0 +1,335 @@
+//! Support for the binary `COPY` format
+//!
+//! `BinaryCopyWriter` encodes rows of `ToSql` values in the format expected by
+//! `COPY ... FROM STDIN (FORMAT binary)`, and `BinaryCopyReader` decodes the
+//! output of `COPY ... TO STDOUT (FORMAT binary)` into rows of `FromSql`
+//! values. They are used along with `copy_in` and `copy_out`:
+//!
+//! ```rust
+//! let mut writer = BinaryCopyWriter::new(MemWriter::new(),
+//!                                        ~[PgInt4, PgVarchar]);
+//! writer.write_row([&1i32 as &ToSql, &"joe" as &ToSql]);
+//! let mut data = MemReader::new(writer.finish().inner());
+//! conn.copy_in("COPY foo (id, name) FROM STDIN (FORMAT binary)", &mut data);
+//! ```
+
+use std::io;
+use std::io::{IoError, io_error};
+use std::io::mem::{MemWriter, BufReader};
+use std::rc::Rc;
+use std::vec;
+
+use super::error::{PostgresError,
+                   PgWrongParamCount,
+                   PgInvalidColumn,
+                   PgBadCopyData,
+                   PgCopyStreamError};
+use super::types::{PostgresType, ToSql, FromSql};
+
+static SIGNATURE: &'static [u8] = bytes!("PGCOPY\n", 0xff, "\r\n", 0);
+
+// Set if each tuple is preceded by an OID, which isn't supported
+static FLAG_HAS_OIDS: i32 = 1 << 16;
+
+fn write_header(buf: &mut MemWriter) {
+    buf.write(SIGNATURE);
+    // flags
+    buf.write_be_i32(0);
+    // header extension length
+    buf.write_be_i32(0);
+}
+
+/// Encodes rows in the binary `COPY` format.
+pub struct BinaryCopyWriter<W> {
+    priv writer: W,
+    priv types: ~[PostgresType],
+    priv started: bool
+}
+
+impl<W: Writer> BinaryCopyWriter<W> {
+    /// Creates a new writer of rows whose columns have the specified types.
+    ///
+    /// The header is written along with the first row.
+    pub fn new(writer: W, types: ~[PostgresType]) -> BinaryCopyWriter<W> {
+        BinaryCopyWriter {
+            writer: writer,
+            types: types,
+            started: false
+        }
+    }
+
+    /// Attempts to write a row.
+    ///
+    /// Returns `PgWrongParamCount` if the number of values doesn't match the
+    /// number of columns, and an error from the `ToSql` implementation if a
+    /// value can't be converted to its column's type. Nothing is written in
+    /// either case. An error raised by the underlying writer is returned as a
+    /// `PgCopyStreamError`.
+    pub fn try_write_row(&mut self, row: &[&ToSql])
+            -> Result<(), PostgresError> {
+        if row.len() != self.types.len() {
+            return Err(PgWrongParamCount {
+                expected: self.types.len(),
+                actual: row.len()
+            });
+        }
+
+        let mut buf = MemWriter::new();
+        if !self.started {
+            write_header(&mut buf);
+        }
+
+        buf.write_be_i16(row.len() as i16);
+        for (value, ty) in row.iter().zip(self.types.iter()) {
+            // Text values are only produced for textual types, whose binary
+            // encoding is identical
+            match value.to_sql(ty) {
+                Ok((_, Some(raw))) => {
+                    buf.write_be_i32(raw.len() as i32);
+                    buf.write(raw);
+                }
+                Ok((_, None)) => buf.write_be_i32(-1),
+                Err(err) => return Err(err)
+            }
+        }
+
+        match self.write(buf.inner()) {
+            Ok(()) => {
+                self.started = true;
+                Ok(())
+            }
+            Err(err) => Err(err)
+        }
+    }
+
+    /// A convenience wrapper around `try_write_row`.
+    ///
+    /// # Failure
+    ///
+    /// Fails if there was an error writing the row.
+    pub fn write_row(&mut self, row: &[&ToSql]) {
+        match self.try_write_row(row) {
+            Ok(()) => {}
+            Err(err) => fail!("Error writing row: {}", err.to_str())
+        }
+    }
+
+    /// Attempts to write the trailer, returning the underlying writer.
+    pub fn try_finish(self) -> Result<W, PostgresError> {
+        let mut this = self;
+        let mut buf = MemWriter::new();
+        if !this.started {
+            write_header(&mut buf);
+        }
+        buf.write_be_i16(-1);
+
+        match this.write(buf.inner()) {
+            Ok(()) => Ok(this.writer),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// A convenience wrapper around `try_finish`.
+    ///
+    /// # Failure
+    ///
+    /// Fails if there was an error writing the trailer.
+    pub fn finish(self) -> W {
+        match self.try_finish() {
+            Ok(writer) => writer,
+            Err(err) => fail!("Error finishing COPY data: {}", err.to_str())
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), PostgresError> {
+        let mut err = None;
+        io_error::cond.trap(|e| err = Some(e)).inside(|| {
+            self.writer.write(buf)
+        });
+
+        match err {
+            Some(err) => Err(PgCopyStreamError(err)),
+            None => Ok(())
+        }
+    }
+}
+
+/// Decodes rows in the binary `COPY` format.
+pub struct BinaryCopyReader<R> {
+    priv reader: R,
+    priv types: Rc<~[PostgresType]>,
+    priv started: bool,
+    priv finished: bool
+}
+
+impl<R: Reader> BinaryCopyReader<R> {
+    /// Creates a new reader of rows whose columns have the specified types.
+    ///
+    /// The header is read along with the first row.
+    pub fn new(reader: R, types: ~[PostgresType]) -> BinaryCopyReader<R> {
+        BinaryCopyReader {
+            reader: reader,
+            types: Rc::new(types),
+            started: false,
+            finished: false
+        }
+    }
+
+    /// Attempts to read the next row.
+    ///
+    /// Returns `PgBadCopyData` if the data is malformed or its rows don't
+    /// have the expected number of columns, and a `PgCopyStreamError` if the
+    /// underlying reader raises an error. No more rows are read after an
+    /// error.
+    pub fn try_next(&mut self)
+            -> Option<Result<BinaryCopyRow, PostgresError>> {
+        if self.finished {
+            return None;
+        }
+
+        match self.read_row() {
+            Ok(Some(values)) => Some(Ok(BinaryCopyRow {
+                types: self.types.clone(),
+                values: values
+            })),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(err) => {
+                self.finished = true;
+                Some(Err(err))
+            }
+        }
+    }
+
+    fn read_row(&mut self) -> Result<Option<~[Option<~[u8]>]>, PostgresError> {
+        if !self.started {
+            match self.read_header() {
+                Ok(()) => self.started = true,
+                Err(err) => return Err(err)
+            }
+        }
+
+        let len = match self.read_int(2) {
+            Ok(-1) => return Ok(None),
+            Ok(len) => len as uint,
+            Err(err) => return Err(err)
+        };
+        if len != self.types.borrow().len() {
+            return Err(PgBadCopyData);
+        }
+
+        let mut values = vec::with_capacity(len);
+        for _ in range(0, len) {
+            match self.read_int(4) {
+                Ok(-1) => values.push(None),
+                Ok(len) if len >= 0 => match self.read_exact(len as uint) {
+                    Ok(value) => values.push(Some(value)),
+                    Err(err) => return Err(err)
+                },
+                Ok(_) => return Err(PgBadCopyData),
+                Err(err) => return Err(err)
+            }
+        }
+
+        Ok(Some(values))
+    }
+
+    fn read_header(&mut self) -> Result<(), PostgresError> {
+        match self.read_exact(SIGNATURE.len()) {
+            Ok(ref signature) if signature.as_slice() == SIGNATURE => {}
+            Ok(_) => return Err(PgBadCopyData),
+            Err(err) => return Err(err)
+        }
+
+        match self.read_int(4) {
+            Ok(flags) if flags & FLAG_HAS_OIDS == 0 => {}
+            Ok(_) => return Err(PgBadCopyData),
+            Err(err) => return Err(err)
+        }
+
+        // The header extension area is reserved for future use
+        match self.read_int(4) {
+            Ok(len) if len >= 0 => self.read_exact(len as uint).map(|_| ()),
+            Ok(_) => Err(PgBadCopyData),
+            Err(err) => Err(err)
+        }
+    }
+
+    // Reads a big endian, signed integer of the specified width
+    fn read_int(&mut self, nbytes: uint) -> Result<i32, PostgresError> {
+        self.read_exact(nbytes).map(|buf| {
+            BufReader::new(buf.as_slice()).read_be_int_n(nbytes) as i32
+        })
+    }
+
+    fn read_exact(&mut self, len: uint) -> Result<~[u8], PostgresError> {
+        let mut err = None;
+        let buf = io_error::cond.trap(|e| err = Some(e)).inside(|| {
+            self.reader.read_bytes(len)
+        });
+
+        match err {
+            None => Ok(buf),
+            Some(IoError { kind: io::EndOfFile, .. }) => Err(PgBadCopyData),
+            Some(err) => Err(PgCopyStreamError(err))
+        }
+    }
+}
+
+impl<R: Reader> Iterator<BinaryCopyRow> for BinaryCopyReader<R> {
+    /// Reads the next row.
+    ///
+    /// # Failure
+    ///
+    /// Fails if there is an error reading the row.
+    fn next(&mut self) -> Option<BinaryCopyRow> {
+        self.try_next().map(|res| {
+            match res {
+                Ok(row) => row,
+                Err(err) => fail!("Error reading row: {}", err.to_str())
+            }
+        })
+    }
+}
+
+/// A row read from binary `COPY` data.
+pub struct BinaryCopyRow {
+    priv types: Rc<~[PostgresType]>,
+    priv values: ~[Option<~[u8]>]
+}
+
+impl Container for BinaryCopyRow {
+    #[inline]
+    fn len(&self) -> uint {
+        self.values.len()
+    }
+}
+
+impl BinaryCopyRow {
+    /// Retrieves the contents of a field of the row.
+    ///
+    /// Like `PostgresRow`, fields are 1-indexed. Returns `PgInvalidColumn` if
+    /// there is no such field, or an error from the `FromSql` implementation
+    /// if the value can't be converted.
+    pub fn try_get<T: FromSql>(&self, idx: uint) -> Result<T, PostgresError> {
+        if idx == 0 || idx > self.values.len() {
+            return Err(PgInvalidColumn);
+        }
+        FromSql::from_sql(&self.types.borrow()[idx - 1],
+                          &self.values[idx - 1])
+    }
+
+    /// A convenience wrapper around `try_get`.
+    ///
+    /// # Failure
+    ///
+    /// Fails if there is no such field or the value can't be converted.
+    pub fn get<T: FromSql>(&self, idx: uint) -> T {
+        match self.try_get(idx) {
+            Ok(val) => val,
+            Err(err) => fail!("Error retrieving column: {}", err.to_str())
+        }
+    }
+}
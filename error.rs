@@ -1,9 +1,12 @@
 //! Postgres errors
 
 use std::hashmap::HashMap;
+use std::io::IoError;
 
 use openssl::ssl::error::SslError;
 
+use super::types::PostgresType;
+
 macro_rules! make_errors(
     ($($code:pat => $error:ident),+) => (
         /// SQLSTATE error codes
@@ -348,14 +351,19 @@ make_errors!(
 /// Reasons a new Postgres connection could fail
 #[deriving(ToStr)]
 pub enum PostgresConnectError {
-    /// The provided URL could not be parsed
+    /// The provided URL or connection string could not be parsed
     InvalidUrl,
+    /// The specified connection parameter had an invalid value
+    InvalidParam(~str),
     /// The URL was missing a user
     MissingUser,
     /// DNS lookup failed
     DnsError,
-    /// There was an error opening a socket to the server
-    SocketError,
+    /// There was an error opening a socket to the server or communicating
+    /// with it while the connection was being set up
+    SocketError(IoError),
+    /// The server did not accept the connection within the connect timeout
+    TimedOut,
     /// An error from the Postgres server itself
     DbError(PostgresDbError),
     /// A password was required but not provided in the URL
@@ -363,10 +371,37 @@ pub enum PostgresConnectError {
     /// The Postgres server requested an authentication method not supported
     /// by the driver
     UnsupportedAuthentication,
+    /// The Postgres server sent a malformed authentication message
+    BadResponse,
+    /// The Postgres server failed to prove that it knows the password during
+    /// SCRAM authentication
+    InvalidServerSignature,
     /// The Postgres server does not support SSL encryption
     NoSslSupport,
+    /// Channel binding was required but the connection does not use SSL or
+    /// the server did not offer SCRAM-SHA-256-PLUS authentication
+    NoChannelBindingSupport,
     /// There was an error initializing the SSL session
-    SslError(SslError)
+    SslError(SslError),
+    /// The server does not support the minimum protocol version requested
+    UnsupportedProtocolVersion,
+    /// The server's SSL certificate was not signed by a trusted certificate
+    /// authority
+    UntrustedCertificate,
+    /// The server's SSL certificate was not issued for the host being
+    /// connected to
+    HostnameMismatch,
+    /// The server did not select the `postgresql` ALPN protocol during a
+    /// direct SSL handshake
+    BadAlpnProtocol,
+    /// The server did not provide the kind of session required by the
+    /// `target_session_attrs` parameter
+    WrongSessionAttrs,
+    /// Some other error occurred while setting up the connection
+    StartupError(PostgresError),
+    /// None of the hosts could be used. Contains each host along with the
+    /// reason it was rejected.
+    AllHostsFailed(~[(~str, PostgresConnectError)])
 }
 
 /// Represents the position of an error in a query
@@ -431,44 +466,78 @@ pub struct PostgresDbError {
     /// they weren't created with constraint syntax.) (PostgreSQL 9.3+)
     constraint: Option<~str>,
     /// The file name of the source-code location where the error was reported.
-    file: ~str,
+    file: Option<~str>,
     /// The line number of the source-code location where the error was
     /// reported.
-    line: uint,
+    line: Option<uint>,
     /// The name of the source-code routine reporting the error.
-    routine: ~str
+    routine: Option<~str>
 }
 
 impl PostgresDbError {
+    /// Parses the fields of an `ErrorResponse` or `NoticeResponse` message.
+    ///
+    /// Returns `PgBadResponse` if the severity, code, or message is missing,
+    /// or if a numeric field can't be parsed.
     #[doc(hidden)]
-    pub fn new(fields: ~[(u8, ~str)]) -> PostgresDbError {
+    pub fn new(fields: ~[(u8, ~str)])
+            -> Result<PostgresDbError, PostgresError> {
         let mut map: HashMap<u8, ~str> = fields.move_iter().collect();
-        PostgresDbError {
-            severity: map.pop(&('S' as u8)).unwrap(),
-            code: FromStr::from_str(map.pop(&('C' as u8)).unwrap()).unwrap(),
-            message: map.pop(&('M' as u8)).unwrap(),
-            detail: map.pop(&('D' as u8)),
-            hint: map.pop(&('H' as u8)),
-            position: match map.pop(&('P' as u8)) {
-                Some(pos) => Some(Position(FromStr::from_str(pos).unwrap())),
-                None => match map.pop(&('p' as u8)) {
-                    Some(pos) => Some(InternalPosition {
-                        position: FromStr::from_str(pos).unwrap(),
-                        query: map.pop(&('q' as u8)).unwrap()
+        let severity = match map.pop(&('S' as u8)) {
+            Some(severity) => severity,
+            None => return Err(PgBadResponse)
+        };
+        let code = match map.pop(&('C' as u8)).and_then(|code| {
+            FromStr::from_str(code)
+        }) {
+            Some(code) => code,
+            None => return Err(PgBadResponse)
+        };
+        let message = match map.pop(&('M' as u8)) {
+            Some(message) => message,
+            None => return Err(PgBadResponse)
+        };
+        let position = match (map.pop(&('P' as u8)), map.pop(&('p' as u8))) {
+            (Some(pos), _) => match FromStr::from_str(pos) {
+                Some(pos) => Some(Position(pos)),
+                None => return Err(PgBadResponse)
+            },
+            (None, Some(pos)) => {
+                match (FromStr::from_str(pos), map.pop(&('q' as u8))) {
+                    (Some(pos), Some(query)) => Some(InternalPosition {
+                        position: pos,
+                        query: query
                     }),
-                    None => None
+                    _ => return Err(PgBadResponse)
                 }
+            }
+            (None, None) => None
+        };
+        let line = match map.pop(&('L' as u8)) {
+            Some(line) => match FromStr::from_str(line) {
+                Some(line) => Some(line),
+                None => return Err(PgBadResponse)
             },
+            None => None
+        };
+
+        Ok(PostgresDbError {
+            severity: severity,
+            code: code,
+            message: message,
+            detail: map.pop(&('D' as u8)),
+            hint: map.pop(&('H' as u8)),
+            position: position,
             where: map.pop(&('W' as u8)),
             schema: map.pop(&('s' as u8)),
             table: map.pop(&('t' as u8)),
             column: map.pop(&('c' as u8)),
             datatype: map.pop(&('d' as u8)),
             constraint: map.pop(&('n' as u8)),
-            file: map.pop(&('F' as u8)).unwrap(),
-            line: FromStr::from_str(map.pop(&('L' as u8)).unwrap()).unwrap(),
-            routine: map.pop(&('R' as u8)).unwrap()
-        }
+            file: map.pop(&('F' as u8)),
+            line: line,
+            routine: map.pop(&('R' as u8))
+        })
     }
 
     #[doc(hidden)]
@@ -486,3 +555,71 @@ impl PostgresDbError {
         }
     }
 }
+
+/// An error encountered when communicating with the Postgres server
+#[deriving(ToStr)]
+pub enum PostgresError {
+    /// An error reported by the Postgres server
+    PgDbError(PostgresDbError),
+    /// An error communicating with the Postgres server
+    PgStreamError(IoError),
+    /// The Postgres server did not respond within the I/O timeout
+    PgTimedOut,
+    /// The Postgres server sent a message that does not follow the protocol
+    PgBadResponse,
+    /// The wrong number of parameters was provided to a statement
+    PgWrongParamCount {
+        /// The number of parameters the statement takes
+        expected: uint,
+        /// The number of parameters provided
+        actual: uint
+    },
+    /// A value was converted to or from an incompatible Postgres type
+    PgWrongType(PostgresType),
+    /// There is no column with the specified index or name
+    PgInvalidColumn,
+    /// A `NULL` value was converted into a type that cannot represent it
+    PgWasNull,
+    /// An earlier exchange with the Postgres server was interrupted, leaving
+    /// the connection in an unknown state
+    PgStreamDesynchronized,
+    /// A statement was used with a connection other than the one that
+    /// prepared it
+    PgWrongConnection,
+    /// The query passed to a `COPY` method was not a `COPY` in the matching
+    /// direction
+    PgWrongCopyQuery,
+    /// An error reading from or writing to the stream passed to a `COPY`
+    /// method
+    PgCopyStreamError(IoError),
+    /// Data in the binary `COPY` format was malformed
+    PgBadCopyData,
+    /// The connection is in use by a streaming query result
+    PgConnectionBusy
+}
+
+impl PostgresError {
+    #[doc(hidden)]
+    pub fn pretty_error(&self, query: &str) -> ~str {
+        match *self {
+            PgDbError(ref err) => err.pretty_error(query),
+            ref err => format!("{} in\n{}", err.to_str(), query)
+        }
+    }
+}
+
+/// The reason a batch of statements failed
+#[deriving(ToStr)]
+pub struct PostgresBatchError {
+    /// The index of the statement which failed. If the error occurred after
+    /// every statement had completed, this is the length of the batch.
+    index: uint,
+    /// The error that occurred
+    error: PostgresError,
+    /// The number of rows modified by each of the statements before the one
+    /// which failed
+    counts: ~[uint],
+    /// The number of statements after the one which failed. They were
+    /// skipped by the server.
+    skipped: uint
+}
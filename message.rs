@@ -1,12 +1,14 @@
 use std::str;
-use std::io::Decorator;
-use std::io::mem::{MemWriter, MemReader};
+use std::io;
+use std::io::{Decorator, IoError, io_error};
+use std::io::mem::{MemWriter, MemReader, BufReader};
 use std::mem;
 use std::vec;
 
+use super::error::{PostgresError, PgStreamError, PgTimedOut, PgBadResponse};
+use super::socket;
 use super::types::Oid;
 
-pub static PROTOCOL_VERSION: i32 = 0x0003_0000;
 pub static CANCEL_CODE: i32 = 80877102;
 pub static SSL_CODE: i32 = 80877103;
 
@@ -21,15 +23,37 @@ pub enum BackendMessage {
     AuthenticationSCMCredential,
     AuthenticationGSS,
     AuthenticationSSPI,
+    AuthenticationSASL {
+        mechanisms: ~[~str]
+    },
+    AuthenticationSASLContinue {
+        data: ~[u8]
+    },
+    AuthenticationSASLFinal {
+        data: ~[u8]
+    },
     BackendKeyData {
         process_id: i32,
-        secret_key: i32
+        secret_key: ~[u8]
     },
     BindComplete,
     CloseComplete,
     CommandComplete {
         tag: ~str
     },
+    CopyInResponse {
+        format: u8,
+        column_formats: ~[u16]
+    },
+    // CopyData and CopyDone, named apart from the frontend messages
+    CopyOutData {
+        data: ~[u8]
+    },
+    CopyOutDone,
+    CopyOutResponse {
+        format: u8,
+        column_formats: ~[u16]
+    },
     DataRow {
         row: ~[Option<~[u8]>]
     },
@@ -37,6 +61,10 @@ pub enum BackendMessage {
     ErrorResponse {
         fields: ~[(u8, ~str)]
     },
+    NegotiateProtocolVersion {
+        minor_version: i32,
+        options: ~[~str]
+    },
     NoData,
     NoticeResponse {
         fields: ~[(u8, ~str)]
@@ -85,12 +113,19 @@ pub enum FrontendMessage<'a> {
     CancelRequest {
         code: i32,
         process_id: i32,
-        secret_key: i32,
+        secret_key: &'a [u8],
     },
     Close {
         variant: u8,
         name: &'a str
     },
+    CopyData {
+        data: &'a [u8]
+    },
+    CopyDone,
+    CopyFail {
+        message: &'a str
+    },
     Describe {
         variant: u8,
         name: &'a str
@@ -99,6 +134,7 @@ pub enum FrontendMessage<'a> {
         portal: &'a str,
         max_rows: i32
     },
+    Flush,
     Parse {
         name: &'a str,
         query: &'a str,
@@ -110,6 +146,13 @@ pub enum FrontendMessage<'a> {
     Query {
         query: &'a str
     },
+    SASLInitialResponse {
+        mechanism: &'a str,
+        data: &'a [u8]
+    },
+    SASLResponse {
+        data: &'a [u8]
+    },
     StartupMessage {
         version: i32,
         parameters: &'a [(~str, ~str)]
@@ -174,13 +217,24 @@ impl<W: Writer> WriteMessage for W {
             CancelRequest { code, process_id, secret_key } => {
                 buf.write_be_i32(code);
                 buf.write_be_i32(process_id);
-                buf.write_be_i32(secret_key);
+                buf.write(secret_key);
             }
             Close { variant, name } => {
                 ident = Some('C');
                 buf.write_u8(variant);
                 buf.write_cstr(name);
             }
+            CopyData { data } => {
+                ident = Some('d');
+                buf.write(data);
+            }
+            CopyDone => {
+                ident = Some('c');
+            }
+            CopyFail { message } => {
+                ident = Some('f');
+                buf.write_cstr(message);
+            }
             Describe { variant, name } => {
                 ident = Some('D');
                 buf.write_u8(variant);
@@ -191,6 +245,9 @@ impl<W: Writer> WriteMessage for W {
                 buf.write_cstr(portal);
                 buf.write_be_i32(max_rows);
             }
+            Flush => {
+                ident = Some('H');
+            }
             Parse { name, query, param_types } => {
                 ident = Some('P');
                 buf.write_cstr(name);
@@ -208,6 +265,16 @@ impl<W: Writer> WriteMessage for W {
                 ident = Some('Q');
                 buf.write_cstr(query);
             }
+            SASLInitialResponse { mechanism, data } => {
+                ident = Some('p');
+                buf.write_cstr(mechanism);
+                buf.write_be_i32(data.len() as i32);
+                buf.write(data);
+            }
+            SASLResponse { data } => {
+                ident = Some('p');
+                buf.write(data);
+            }
             StartupMessage { version, parameters } => {
                 buf.write_be_i32(version);
                 for &(ref k, ref v) in parameters.iter() {
@@ -242,62 +309,166 @@ trait ReadCStr {
 }
 
 impl<R: Buffer> ReadCStr for R {
+    // Malformed strings raise io_error so the message is rejected
     fn read_cstr(&mut self) -> ~str {
-        let mut buf = self.read_until(0).unwrap();
-        buf.pop();
-        str::from_utf8_owned(buf)
+        let mut buf = self.read_until(0).unwrap_or(~[]);
+        if buf.pop_opt() != Some(0) {
+            io_error::cond.raise(malformed_error("missing null terminator"));
+            return ~"";
+        }
+        match str::from_utf8_owned_opt(buf) {
+            Some(s) => s,
+            None => {
+                io_error::cond.raise(malformed_error("invalid UTF-8"));
+                ~""
+            }
+        }
+    }
+}
+
+fn malformed_error(desc: &'static str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+/// Converts an error from the underlying stream into a `PostgresError`.
+pub fn stream_error(err: IoError) -> PostgresError {
+    if socket::is_timeout(&err) {
+        PgTimedOut
+    } else {
+        PgStreamError(err)
+    }
+}
+
+// Reads exactly `len` bytes from the stream
+fn read_exact<R: Reader>(rdr: &mut R, len: uint)
+        -> Result<~[u8], PostgresError> {
+    let mut err = None;
+    let buf = io_error::cond.trap(|e| err = Some(e)).inside(|| {
+        let mut buf = vec::from_elem(len, 0u8);
+        let mut pos = 0;
+        while pos < len {
+            match rdr.read(buf.mut_slice_from(pos)) {
+                Some(n) => pos += n,
+                None => return None
+            }
+        }
+        Some(buf)
+    });
+
+    match (buf, err) {
+        (_, Some(err)) => Err(stream_error(err)),
+        (Some(buf), None) => Ok(buf),
+        (None, None) => Err(PgStreamError(IoError {
+            kind: io::EndOfFile,
+            desc: "unexpected end of stream",
+            detail: None
+        }))
     }
 }
 
 pub trait ReadMessage {
-    fn read_message(&mut self) -> BackendMessage;
+    fn read_message(&mut self) -> Result<BackendMessage, PostgresError>;
 }
 
 impl<R: Reader> ReadMessage for R {
-    fn read_message(&mut self) -> BackendMessage {
+    fn read_message(&mut self) -> Result<BackendMessage, PostgresError> {
         debug!("Reading message");
 
-        let ident = self.read_u8();
+        let header = match read_exact(self, 1 + mem::size_of::<i32>()) {
+            Ok(header) => header,
+            Err(err) => return Err(err)
+        };
+        let ident = header[0];
+        let len = BufReader::new(header.slice_from(1)).read_be_i32();
         // subtract size of length value
-        let len = self.read_be_i32() as uint - mem::size_of::<i32>();
-        let mut buf = MemReader::new(self.read_bytes(len));
-
-        let ret = match ident as char {
-            '1' => ParseComplete,
-            '2' => BindComplete,
-            '3' => CloseComplete,
-            'A' => NotificationResponse {
-                pid: buf.read_be_i32(),
-                channel: buf.read_cstr(),
-                payload: buf.read_cstr()
-            },
-            'C' => CommandComplete { tag: buf.read_cstr() },
-            'D' => read_data_row(&mut buf),
-            'E' => ErrorResponse { fields: read_fields(&mut buf) },
-            'I' => EmptyQueryResponse,
-            'K' => BackendKeyData {
-                process_id: buf.read_be_i32(),
-                secret_key: buf.read_be_i32()
-            },
-            'n' => NoData,
-            'N' => NoticeResponse { fields: read_fields(&mut buf) },
-            'R' => read_auth_message(&mut buf),
-            's' => PortalSuspended,
-            'S' => ParameterStatus {
-                parameter: buf.read_cstr(),
-                value: buf.read_cstr()
-            },
-            't' => read_parameter_description(&mut buf),
-            'T' => read_row_description(&mut buf),
-            'Z' => ReadyForQuery { state: buf.read_u8() },
-            ident => fail!("Unknown message identifier `{}`", ident)
+        if len < mem::size_of::<i32>() as i32 {
+            return Err(PgBadResponse);
+        }
+        let len = len as uint - mem::size_of::<i32>();
+        let mut buf = match read_exact(self, len) {
+            Ok(body) => MemReader::new(body),
+            Err(err) => return Err(err)
         };
-        assert!(buf.eof());
-        debug!("Read message {:?}", ret);
-        ret
+
+        // Reading past the end of the message raises io_error
+        let mut malformed = false;
+        let ret = io_error::cond.trap(|_| malformed = true).inside(|| {
+            read_body(ident, &mut buf)
+        });
+        if malformed || !buf.eof() {
+            return Err(PgBadResponse);
+        }
+
+        match ret {
+            Some(ret) => {
+                debug!("Read message {:?}", ret);
+                Ok(ret)
+            }
+            None => Err(PgBadResponse)
+        }
     }
 }
 
+fn read_body(ident: u8, buf: &mut MemReader) -> Option<BackendMessage> {
+    let ret = match ident as char {
+        '1' => ParseComplete,
+        '2' => BindComplete,
+        '3' => CloseComplete,
+        'A' => NotificationResponse {
+            pid: buf.read_be_i32(),
+            channel: buf.read_cstr(),
+            payload: buf.read_cstr()
+        },
+        'c' => CopyOutDone,
+        'C' => CommandComplete { tag: buf.read_cstr() },
+        'd' => CopyOutData { data: buf.read_to_end() },
+        'D' => read_data_row(buf),
+        'E' => ErrorResponse { fields: read_fields(buf) },
+        'G' => {
+            let (format, column_formats) = read_copy_response(buf);
+            CopyInResponse {
+                format: format,
+                column_formats: column_formats
+            }
+        }
+        'H' => {
+            let (format, column_formats) = read_copy_response(buf);
+            CopyOutResponse {
+                format: format,
+                column_formats: column_formats
+            }
+        }
+        'I' => EmptyQueryResponse,
+        // The key is 4 bytes long before protocol 3.2, and up to 256
+        // bytes after
+        'K' => BackendKeyData {
+            process_id: buf.read_be_i32(),
+            secret_key: buf.read_to_end()
+        },
+        'n' => NoData,
+        'N' => NoticeResponse { fields: read_fields(buf) },
+        's' => PortalSuspended,
+        'S' => ParameterStatus {
+            parameter: buf.read_cstr(),
+            value: buf.read_cstr()
+        },
+        't' => read_parameter_description(buf),
+        'v' => read_negotiate_protocol_version(buf),
+        'T' => read_row_description(buf),
+        'R' => match read_auth_message(buf) {
+            Some(msg) => msg,
+            None => return None
+        },
+        'Z' => ReadyForQuery { state: buf.read_u8() },
+        _ => return None
+    };
+    Some(ret)
+}
+
 fn read_fields(buf: &mut MemReader) -> ~[(u8, ~str)] {
     let mut fields = ~[];
     loop {
@@ -312,6 +483,18 @@ fn read_fields(buf: &mut MemReader) -> ~[(u8, ~str)] {
     fields
 }
 
+fn read_copy_response(buf: &mut MemReader) -> (u8, ~[u16]) {
+    let format = buf.read_u8();
+    let len = buf.read_be_i16() as uint;
+    let mut column_formats = vec::with_capacity(len);
+
+    for _ in range(0, len) {
+        column_formats.push(buf.read_be_u16());
+    }
+
+    (format, column_formats)
+}
+
 fn read_data_row(buf: &mut MemReader) -> BackendMessage {
     let len = buf.read_be_i16() as uint;
     let mut values = vec::with_capacity(len);
@@ -319,7 +502,11 @@ fn read_data_row(buf: &mut MemReader) -> BackendMessage {
     for _ in range(0, len) {
         let val = match buf.read_be_i32() {
             -1 => None,
-            len => Some(buf.read_bytes(len as uint))
+            len if len >= 0 => Some(buf.read_bytes(len as uint)),
+            _ => {
+                io_error::cond.raise(malformed_error("invalid value length"));
+                None
+            }
         };
         values.push(val);
     }
@@ -327,8 +514,8 @@ fn read_data_row(buf: &mut MemReader) -> BackendMessage {
     DataRow { row: values }
 }
 
-fn read_auth_message(buf: &mut MemReader) -> BackendMessage {
-    match buf.read_be_i32() {
+fn read_auth_message(buf: &mut MemReader) -> Option<BackendMessage> {
+    let ret = match buf.read_be_i32() {
         0 => AuthenticationOk,
         2 => AuthenticationKerberosV5,
         3 => AuthenticationCleartextPassword,
@@ -336,8 +523,22 @@ fn read_auth_message(buf: &mut MemReader) -> BackendMessage {
         6 => AuthenticationSCMCredential,
         7 => AuthenticationGSS,
         9 => AuthenticationSSPI,
-        val => fail!("Invalid authentication identifier `{}`", val)
-    }
+        10 => {
+            let mut mechanisms = ~[];
+            loop {
+                let mechanism = buf.read_cstr();
+                if mechanism.is_empty() {
+                    break;
+                }
+                mechanisms.push(mechanism);
+            }
+            AuthenticationSASL { mechanisms: mechanisms }
+        }
+        11 => AuthenticationSASLContinue { data: buf.read_to_end() },
+        12 => AuthenticationSASLFinal { data: buf.read_to_end() },
+        _ => return None
+    };
+    Some(ret)
 }
 
 fn read_parameter_description(buf: &mut MemReader) -> BackendMessage {
@@ -351,6 +552,21 @@ fn read_parameter_description(buf: &mut MemReader) -> BackendMessage {
     ParameterDescription { types: types }
 }
 
+fn read_negotiate_protocol_version(buf: &mut MemReader) -> BackendMessage {
+    let minor_version = buf.read_be_i32();
+    let len = buf.read_be_i32() as uint;
+    let mut options = vec::with_capacity(len);
+
+    for _ in range(0, len) {
+        options.push(buf.read_cstr());
+    }
+
+    NegotiateProtocolVersion {
+        minor_version: minor_version,
+        options: options
+    }
+}
+
 fn read_row_description(buf: &mut MemReader) -> BackendMessage {
     let len = buf.read_be_i16() as uint;
     let mut types = vec::with_capacity(len);
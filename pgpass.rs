@@ -0,0 +1,150 @@
+//! Password file lookup
+
+use std::io;
+use std::io::{File, io_error};
+use std::io::buffered::BufferedReader;
+use std::io::fs;
+use std::io::net::ip::Port;
+use std::os;
+
+/// Reads all of the lines of a file, without their line terminators.
+///
+/// Returns `None` if the file cannot be read.
+pub fn read_lines(path: &Path) -> Option<~[~str]> {
+    io_error::cond.trap(|_| {}).inside(|| {
+        File::open(path).map(|file| {
+            let mut reader = BufferedReader::new(file);
+            reader.lines().map(|line| {
+                line.trim_right_chars(& &['\n', '\r']).to_owned()
+            }).collect()
+        })
+    })
+}
+
+/// Looks up the password for a connection in the password file.
+///
+/// The file is `file` if specified, or else located by the `PGPASSFILE`
+/// environment variable, defaulting to `~/.pgpass`. Like libpq, files that
+/// are readable or writable by group or other users are ignored.
+pub fn find_password(file: &Option<~str>, host: &str, port: Port,
+                     database: &str, user: &str) -> Option<~str> {
+    let path = match password_file(file) {
+        Some(path) => path,
+        None => return None
+    };
+
+    if !check_permissions(&path) {
+        warn!("Ignoring password file {} since its permissions are too open",
+              path.display());
+        return None;
+    }
+
+    let lines = match read_lines(&path) {
+        Some(lines) => lines,
+        None => return None
+    };
+
+    let port = port.to_str();
+    for line in lines.iter() {
+        if line.starts_with("#") {
+            continue;
+        }
+
+        let fields = split_fields(*line);
+        if fields.len() != 5 {
+            continue;
+        }
+
+        if field_matches(fields[0], host) && field_matches(fields[1], port)
+                && field_matches(fields[2], database)
+                && field_matches(fields[3], user) {
+            return Some(fields[4].clone());
+        }
+    }
+
+    None
+}
+
+fn password_file(file: &Option<~str>) -> Option<Path> {
+    match file.clone().or_else(|| os::getenv("PGPASSFILE")) {
+        Some(path) => return Some(Path::new(path)),
+        None => {}
+    }
+    default_password_file()
+}
+
+#[cfg(unix)]
+fn default_password_file() -> Option<Path> {
+    os::homedir().map(|home| home.join(".pgpass"))
+}
+
+#[cfg(windows)]
+fn default_password_file() -> Option<Path> {
+    os::getenv("APPDATA").map(|appdata| {
+        Path::new(appdata).join_many(["postgresql", "pgpass.conf"])
+    })
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> bool {
+    let stat = io_error::cond.trap(|_| {}).inside(|| fs::stat(path));
+    stat.perm & (io::GroupRWX | io::OtherRWX) == 0
+}
+
+#[cfg(windows)]
+fn check_permissions(_path: &Path) -> bool {
+    true
+}
+
+// Fields are separated by `:`, which can be escaped along with `\` itself.
+// Everything after the fourth separator is the password, so it may contain
+// unescaped colons.
+fn split_fields(line: &str) -> ~[~str] {
+    let mut fields = ~[];
+    let mut field = ~"";
+    let mut escaped = false;
+
+    for c in line.chars() {
+        if escaped {
+            field.push_char(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == ':' && fields.len() < 4 {
+            fields.push(::std::util::replace(&mut field, ~""));
+        } else {
+            field.push_char(c);
+        }
+    }
+    fields.push(field);
+
+    fields
+}
+
+fn field_matches(field: &str, value: &str) -> bool {
+    field == "*" || field == value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_fields;
+
+    #[test]
+    fn test_split_fields() {
+        assert_eq!(~[~"host", ~"5432", ~"db", ~"user", ~"pass"],
+                   split_fields("host:5432:db:user:pass"));
+        assert_eq!(~[~"ho:st", ~"*", ~"db", ~"us\\er", ~"pass"],
+                   split_fields("ho\\:st:*:db:us\\\\er:pass"));
+    }
+
+    #[test]
+    fn test_split_fields_colon_in_password() {
+        assert_eq!(~[~"host", ~"5432", ~"db", ~"user", ~"pa:ss:"],
+                   split_fields("host:5432:db:user:pa:ss:"));
+    }
+
+    #[test]
+    fn test_split_fields_short_line() {
+        assert_eq!(~[~"host", ~"5432", ~"db"], split_fields("host:5432:db"));
+    }
+}
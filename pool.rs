@@ -2,40 +2,47 @@
 
 extern mod extra;
 
-use extra::arc::MutexArc;
+use extra::arc::{Arc, MutexArc};
 
 use super::{PostgresNotificationIterator,
             PostgresCancelData,
             PostgresConnection,
+            PostgresConnectParams,
+            IntoConnectParams,
             NormalPostgresStatement,
             PostgresTransaction,
-            SslMode};
-use super::error::{PostgresConnectError, PostgresDbError};
-use super::types::ToSql;
+            PostgresBatch,
+            SimpleQueryResult,
+            SslMode,
+            TransactionStatus,
+            Idle};
+use super::error::{PostgresConnectError, PostgresError};
+use super::types::{PostgresType, ToSql};
 
-struct InnerConnectionPool {
-    url: ~str,
+struct PoolConfig {
+    params: PostgresConnectParams,
     ssl: SslMode,
-    pool: ~[PostgresConnection],
 }
 
-impl InnerConnectionPool {
-    fn new_connection(&mut self) -> Option<PostgresConnectError> {
-        match PostgresConnection::try_connect(self.url, &self.ssl) {
-            Ok(conn) => {
-                self.pool.push(conn);
-                None
-            }
-            Err(err) => Some(err)
-        }
+impl PoolConfig {
+    fn connect(&self) -> Result<PostgresConnection, PostgresConnectError> {
+        PostgresConnection::try_connect_params(&self.params, &self.ssl)
     }
 }
 
+struct InnerConnectionPool {
+    pool: ~[PostgresConnection],
+    // Connections which were discarded when returned to the pool
+    discarded: uint,
+}
+
 /// A simple fixed-size Postgres connection pool.
 ///
 /// It can be shared across tasks.
 #[deriving(Clone)]
 pub struct PostgresConnectionPool {
+    // New connections are opened without holding the pool's lock
+    priv config: Arc<PoolConfig>,
     priv pool: MutexArc<InnerConnectionPool>
 }
 
@@ -44,22 +51,31 @@ impl PostgresConnectionPool {
     ///
     /// Returns an error if the specified number of connections cannot be
     /// created.
-    pub fn try_new(url: &str, ssl: SslMode, pool_size: uint)
+    pub fn try_new<T: IntoConnectParams>(params: T, ssl: SslMode,
+                                         pool_size: uint)
             -> Result<PostgresConnectionPool, PostgresConnectError> {
-        let mut pool = InnerConnectionPool {
-            url: url.to_owned(),
+        let params = match params.into_connect_params() {
+            Ok(params) => params,
+            Err(err) => return Err(err)
+        };
+        let config = PoolConfig {
+            params: params,
             ssl: ssl,
+        };
+        let mut pool = InnerConnectionPool {
             pool: ~[],
+            discarded: 0,
         };
 
         for _ in range(0, pool_size) {
-            match pool.new_connection() {
-                None => (),
-                Some(err) => return Err(err)
+            match config.connect() {
+                Ok(conn) => pool.pool.push(conn),
+                Err(err) => return Err(err)
             }
         }
 
         Ok(PostgresConnectionPool {
+            config: Arc::new(config),
             pool: MutexArc::new(pool)
         })
     }
@@ -69,31 +85,73 @@ impl PostgresConnectionPool {
     /// # Failure
     ///
     /// Fails if the pool cannot be created.
-    pub fn new(url: &str, ssl: SslMode, pool_size: uint)
+    pub fn new<T: IntoConnectParams>(params: T, ssl: SslMode, pool_size: uint)
             -> PostgresConnectionPool {
-        match PostgresConnectionPool::try_new(url, ssl, pool_size) {
+        match PostgresConnectionPool::try_new(params, ssl, pool_size) {
             Ok(pool) => pool,
             Err(err) => fail!("Unable to initialize pool: {}", err.to_str())
         }
     }
 
-    /// Retrieves a connection from the pool.
+    /// Attempts to retrieve a connection from the pool.
     ///
     /// If all connections are in use, blocks until one becomes available.
-    pub fn get_connection(&self) -> PooledPostgresConnection {
+    /// Connections which were discarded when they were returned to the pool
+    /// are replaced with new ones, and an error is returned if a replacement
+    /// cannot be created. Other users of the pool are not blocked while a
+    /// replacement is being created.
+    pub fn try_get_connection(&self)
+            -> Result<PooledPostgresConnection, PostgresConnectError> {
+        // Returns `None` if the slot of a discarded connection was reserved
+        // to be filled by a new one
         let conn = unsafe {
             self.pool.unsafe_access_cond(|pool, cvar| {
-                while pool.pool.is_empty() {
+                loop {
+                    match pool.pool.pop_opt() {
+                        Some(conn) => return Some(conn),
+                        None => {}
+                    }
+                    if pool.discarded > 0 {
+                        pool.discarded -= 1;
+                        return None;
+                    }
                     cvar.wait();
                 }
-
-                pool.pool.pop()
             })
         };
 
-        PooledPostgresConnection {
+        let conn = match conn {
+            Some(conn) => conn,
+            None => match self.config.get().connect() {
+                Ok(conn) => conn,
+                Err(err) => {
+                    // Give the slot back so that a later call can retry
+                    unsafe {
+                        self.pool.unsafe_access_cond(|pool, cvar| {
+                            pool.discarded += 1;
+                            cvar.signal();
+                        })
+                    }
+                    return Err(err);
+                }
+            }
+        };
+
+        Ok(PooledPostgresConnection {
             pool: self.clone(),
             conn: Some(conn)
+        })
+    }
+
+    /// A convenience function wrapping `try_get_connection`.
+    ///
+    /// # Failure
+    ///
+    /// Fails if a replacement connection cannot be created.
+    pub fn get_connection(&self) -> PooledPostgresConnection {
+        match self.try_get_connection() {
+            Ok(conn) => conn,
+            Err(err) => fail!("Unable to replace connection: {}", err.to_str())
         }
     }
 }
@@ -101,7 +159,10 @@ impl PostgresConnectionPool {
 /// A Postgres connection pulled from a connection pool.
 ///
 /// It will be returned to the pool when it falls out of scope, even due to
-/// task failure.
+/// task failure. Any transaction left open on it is rolled back first. A
+/// connection which has been desynchronized, whose last exchange with the
+/// server was interrupted, or which could not be rolled back, is discarded
+/// instead.
 pub struct PooledPostgresConnection {
     priv pool: PostgresConnectionPool,
     // TODO remove the Option wrapper when drop takes self by value
@@ -110,9 +171,24 @@ pub struct PooledPostgresConnection {
 
 impl Drop for PooledPostgresConnection {
     fn drop(&mut self) {
+        {
+            let conn = self.conn.get_ref();
+            conn.check_interrupted();
+            if !conn.is_desynchronized() && conn.transaction_status() != Idle {
+                let _ = conn.quick_query("ROLLBACK");
+            }
+        }
+
         unsafe {
-            self.pool.pool.unsafe_access(|pool| {
-                pool.pool.push(self.conn.take_unwrap());
+            self.pool.pool.unsafe_access_cond(|pool, cvar| {
+                let conn = self.conn.take_unwrap();
+                if conn.is_desynchronized()
+                        || conn.transaction_status() != Idle {
+                    pool.discarded += 1;
+                } else {
+                    pool.pool.push(conn);
+                }
+                cvar.signal();
             })
         }
     }
@@ -121,7 +197,7 @@ impl Drop for PooledPostgresConnection {
 impl PooledPostgresConnection {
     /// Like `PostgresConnection::try_prepare`.
     pub fn try_prepare<'a>(&'a self, query: &str)
-            -> Result<NormalPostgresStatement<'a>, PostgresDbError> {
+            -> Result<NormalPostgresStatement<'a>, PostgresError> {
         self.conn.get_ref().try_prepare(query)
     }
 
@@ -130,9 +206,45 @@ impl PooledPostgresConnection {
         self.conn.get_ref().prepare(query)
     }
 
+    /// Like `PostgresConnection::try_prepare_typed`.
+    pub fn try_prepare_typed<'a>(&'a self, query: &str,
+                                 types: &[PostgresType])
+            -> Result<NormalPostgresStatement<'a>, PostgresError> {
+        self.conn.get_ref().try_prepare_typed(query, types)
+    }
+
+    /// Like `PostgresConnection::prepare_typed`.
+    pub fn prepare_typed<'a>(&'a self, query: &str, types: &[PostgresType])
+            -> NormalPostgresStatement<'a> {
+        self.conn.get_ref().prepare_typed(query, types)
+    }
+
+    /// Like `PostgresConnection::try_prepare_cached`.
+    pub fn try_prepare_cached<'a>(&'a self, query: &str)
+            -> Result<NormalPostgresStatement<'a>, PostgresError> {
+        self.conn.get_ref().try_prepare_cached(query)
+    }
+
+    /// Like `PostgresConnection::prepare_cached`.
+    pub fn prepare_cached<'a>(&'a self, query: &str)
+            -> NormalPostgresStatement<'a> {
+        self.conn.get_ref().prepare_cached(query)
+    }
+
+    /// Like `PostgresConnection::try_set_statement_cache_size`.
+    pub fn try_set_statement_cache_size(&self, size: uint)
+            -> Result<(), PostgresError> {
+        self.conn.get_ref().try_set_statement_cache_size(size)
+    }
+
+    /// Like `PostgresConnection::set_statement_cache_size`.
+    pub fn set_statement_cache_size(&self, size: uint) {
+        self.conn.get_ref().set_statement_cache_size(size)
+    }
+
     /// Like `PostgresConnection::try_execute`.
     pub fn try_execute(&self, query: &str, params: &[&ToSql])
-            -> Result<uint, PostgresDbError> {
+            -> Result<uint, PostgresError> {
         self.conn.get_ref().try_execute(query, params)
     }
 
@@ -141,11 +253,76 @@ impl PooledPostgresConnection {
         self.conn.get_ref().execute(query, params)
     }
 
+    /// Like `PostgresConnection::try_execute_unprepared`.
+    pub fn try_execute_unprepared(&self, query: &str, params: &[&ToSql])
+            -> Result<uint, PostgresError> {
+        self.conn.get_ref().try_execute_unprepared(query, params)
+    }
+
+    /// Like `PostgresConnection::execute_unprepared`.
+    pub fn execute_unprepared(&self, query: &str, params: &[&ToSql]) -> uint {
+        self.conn.get_ref().execute_unprepared(query, params)
+    }
+
+    /// Like `PostgresConnection::try_transaction`.
+    pub fn try_transaction<'a>(&'a self)
+            -> Result<PostgresTransaction<'a>, PostgresError> {
+        self.conn.get_ref().try_transaction()
+    }
+
     /// Like `PostgresConnection::transaction`.
     pub fn transaction<'a>(&'a self) -> PostgresTransaction<'a> {
         self.conn.get_ref().transaction()
     }
 
+    /// Like `PostgresConnection::try_batch_execute`.
+    pub fn try_batch_execute(&self, query: &str) -> Result<(), PostgresError> {
+        self.conn.get_ref().try_batch_execute(query)
+    }
+
+    /// Like `PostgresConnection::batch_execute`.
+    pub fn batch_execute(&self, query: &str) {
+        self.conn.get_ref().batch_execute(query)
+    }
+
+    /// Like `PostgresConnection::try_simple_query`.
+    pub fn try_simple_query(&self, query: &str)
+            -> Result<~[SimpleQueryResult], PostgresError> {
+        self.conn.get_ref().try_simple_query(query)
+    }
+
+    /// Like `PostgresConnection::simple_query`.
+    pub fn simple_query(&self, query: &str) -> ~[SimpleQueryResult] {
+        self.conn.get_ref().simple_query(query)
+    }
+
+    /// Like `PostgresConnection::try_copy_in`.
+    pub fn try_copy_in<R: Reader>(&self, query: &str, reader: &mut R)
+            -> Result<uint, PostgresError> {
+        self.conn.get_ref().try_copy_in(query, reader)
+    }
+
+    /// Like `PostgresConnection::copy_in`.
+    pub fn copy_in<R: Reader>(&self, query: &str, reader: &mut R) -> uint {
+        self.conn.get_ref().copy_in(query, reader)
+    }
+
+    /// Like `PostgresConnection::try_copy_out`.
+    pub fn try_copy_out<W: Writer>(&self, query: &str, writer: &mut W)
+            -> Result<uint, PostgresError> {
+        self.conn.get_ref().try_copy_out(query, writer)
+    }
+
+    /// Like `PostgresConnection::copy_out`.
+    pub fn copy_out<W: Writer>(&self, query: &str, writer: &mut W) -> uint {
+        self.conn.get_ref().copy_out(query, writer)
+    }
+
+    /// Like `PostgresConnection::batch`.
+    pub fn batch<'a>(&'a self) -> PostgresBatch<'a> {
+        self.conn.get_ref().batch()
+    }
+
     /// Like `PostgresConnection::notifications`.
     pub fn notifications<'a>(&'a self) -> PostgresNotificationIterator<'a> {
         self.conn.get_ref().notifications()
@@ -155,4 +332,24 @@ impl PooledPostgresConnection {
     pub fn cancel_data(&self) -> PostgresCancelData {
         self.conn.get_ref().cancel_data()
     }
+
+    /// Like `PostgresConnection::parameter`.
+    pub fn parameter(&self, name: &str) -> Option<~str> {
+        self.conn.get_ref().parameter(name)
+    }
+
+    /// Like `PostgresConnection::server_version`.
+    pub fn server_version(&self) -> Option<(uint, uint)> {
+        self.conn.get_ref().server_version()
+    }
+
+    /// Like `PostgresConnection::transaction_status`.
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.conn.get_ref().transaction_status()
+    }
+
+    /// Like `PostgresConnection::is_desynchronized`.
+    pub fn is_desynchronized(&self) -> bool {
+        self.conn.get_ref().is_desynchronized()
+    }
 }
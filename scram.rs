@@ -0,0 +1,250 @@
+//! The client side of SCRAM-SHA-256 authentication (RFC 5802, RFC 7677)
+
+use extra::base64::{ToBase64, FromBase64, STANDARD};
+use openssl::crypto::hash::{SHA256, Hasher};
+use openssl::crypto::hmac::HMAC;
+use openssl::crypto::rand::rand_bytes;
+use std::str;
+
+pub static SCRAM_SHA_256: &'static str = "SCRAM-SHA-256";
+pub static SCRAM_SHA_256_PLUS: &'static str = "SCRAM-SHA-256-PLUS";
+
+static NONCE_LENGTH: uint = 24;
+
+/// The channel binding state advertised in the GS2 header
+pub enum ScramChannelBinding {
+    /// The client does not support channel binding
+    NotSupported,
+    /// The client supports channel binding but thinks the server does not
+    Unrequested,
+    /// The client is using `tls-server-end-point` channel binding with the
+    /// specified certificate hash
+    TlsServerEndPoint(~[u8])
+}
+
+impl ScramChannelBinding {
+    fn gs2_header(&self) -> &'static str {
+        // Postgres ignores the username in the SCRAM exchange in favor of the
+        // one in the startup message, so the authzid is always left empty.
+        match *self {
+            NotSupported => "n,,",
+            Unrequested => "y,,",
+            TlsServerEndPoint(..) => "p=tls-server-end-point,,"
+        }
+    }
+
+    fn encode(&self) -> ~str {
+        let mut buf = self.gs2_header().as_bytes().to_owned();
+        match *self {
+            TlsServerEndPoint(ref data) => buf.push_all(*data),
+            _ => {}
+        }
+        buf.to_base64(STANDARD)
+    }
+}
+
+enum State {
+    Update {
+        nonce: ~str,
+        password: ~[u8],
+        channel_binding: ScramChannelBinding
+    },
+    Finish {
+        salted_password: ~[u8],
+        auth_message: ~str
+    },
+    Done
+}
+
+/// The reasons a SCRAM exchange can fail on the client side
+pub enum ScramError {
+    /// The server sent a message that does not follow the protocol
+    Malformed,
+    /// The server could not prove that it knows the password
+    BadServerSignature
+}
+
+/// A SCRAM-SHA-256 exchange.
+///
+/// `message` is sent to the server in a `SASLInitialResponse`, `update` is
+/// called with the contents of the server's `AuthenticationSASLContinue`, the
+/// new `message` is sent in a `SASLResponse`, and `finish` is called with the
+/// contents of the server's `AuthenticationSASLFinal`.
+pub struct ScramSha256 {
+    priv message: ~str,
+    priv state: State
+}
+
+impl ScramSha256 {
+    /// Begins an exchange using the specified password.
+    ///
+    /// The password is not normalized with SASLprep. Postgres falls back to
+    /// the raw password bytes when normalization fails, so ASCII passwords,
+    /// and most others, work as-is.
+    pub fn new(password: &[u8], channel_binding: ScramChannelBinding)
+            -> ScramSha256 {
+        let nonce = make_nonce();
+        ScramSha256 {
+            message: format!("{}n=,r={}", channel_binding.gs2_header(), nonce),
+            state: Update {
+                nonce: nonce,
+                password: password.to_owned(),
+                channel_binding: channel_binding
+            }
+        }
+    }
+
+    /// Returns the message to send to the server for the current step.
+    pub fn message<'a>(&'a self) -> &'a [u8] {
+        self.message.as_bytes()
+    }
+
+    /// Processes the server-first-message and computes the client proof.
+    pub fn update(&mut self, message: &[u8]) -> Result<(), ScramError> {
+        let (nonce, password, channel_binding) =
+                match ::std::util::replace(&mut self.state, Done) {
+            Update { nonce, password, channel_binding } =>
+                (nonce, password, channel_binding),
+            _ => return Err(Malformed)
+        };
+
+        let server_first = match str::from_utf8_opt(message) {
+            Some(message) => message,
+            None => return Err(Malformed)
+        };
+
+        let (server_nonce, salt, iterations) =
+                match parse_server_first(server_first) {
+            Some(parsed) => parsed,
+            None => return Err(Malformed)
+        };
+
+        if !server_nonce.starts_with(nonce) {
+            return Err(Malformed);
+        }
+
+        let salted_password = hi(password, salt, iterations);
+        let client_key = hmac(salted_password, "Client Key".as_bytes());
+        let stored_key = sha256(client_key);
+
+        let without_proof = format!("c={},r={}", channel_binding.encode(),
+                                    server_nonce);
+        // the client-first-message-bare is everything after the GS2 header
+        let header_len = channel_binding.gs2_header().len();
+        let auth_message = format!("{},{},{}",
+                                   self.message.slice_from(header_len),
+                                   server_first, without_proof);
+
+        let client_signature = hmac(stored_key, auth_message.as_bytes());
+        let proof: ~[u8] = client_key.iter().zip(client_signature.iter())
+                .map(|(a, b)| *a ^ *b).collect();
+
+        self.message = format!("{},p={}", without_proof,
+                               proof.to_base64(STANDARD));
+        self.state = Finish {
+            salted_password: salted_password,
+            auth_message: auth_message
+        };
+        Ok(())
+    }
+
+    /// Verifies the server signature in the server-final-message.
+    pub fn finish(&mut self, message: &[u8]) -> Result<(), ScramError> {
+        let (salted_password, auth_message) =
+                match ::std::util::replace(&mut self.state, Done) {
+            Finish { salted_password, auth_message } =>
+                (salted_password, auth_message),
+            _ => return Err(Malformed)
+        };
+
+        let server_final = match str::from_utf8_opt(message) {
+            Some(message) => message,
+            None => return Err(Malformed)
+        };
+
+        let signature = match server_final.split(',').next() {
+            Some(attr) if attr.starts_with("v=") =>
+                match attr.slice_from(2).from_base64() {
+                    Ok(signature) => signature,
+                    Err(_) => return Err(Malformed)
+                },
+            _ => return Err(Malformed)
+        };
+
+        let server_key = hmac(salted_password, "Server Key".as_bytes());
+        let expected = hmac(server_key, auth_message.as_bytes());
+
+        // Compare in constant time
+        if signature.len() != expected.len() ||
+                signature.iter().zip(expected.iter())
+                    .fold(0u8, |acc, (a, b)| acc | (*a ^ *b)) != 0 {
+            return Err(BadServerSignature);
+        }
+
+        Ok(())
+    }
+}
+
+// The nonce may contain any printable character other than `,`
+fn make_nonce() -> ~str {
+    rand_bytes(NONCE_LENGTH).iter().map(|b| {
+        let c = (0x21 + *b % (0x7f - 0x21)) as char;
+        if c == ',' { '~' } else { c }
+    }).collect()
+}
+
+fn parse_server_first(message: &str) -> Option<(~str, ~[u8], uint)> {
+    let mut nonce = None;
+    let mut salt = None;
+    let mut iterations = None;
+
+    for attr in message.split(',') {
+        if attr.len() < 2 || attr.char_at(1) != '=' {
+            return None;
+        }
+        let value = attr.slice_from(2);
+        match attr.char_at(0) {
+            'r' => nonce = Some(value.to_owned()),
+            's' => salt = value.from_base64().ok(),
+            'i' => iterations = FromStr::from_str(value),
+            // Mandatory extensions we don't know about must be rejected
+            'm' => return None,
+            _ => {}
+        }
+    }
+
+    match (nonce, salt, iterations) {
+        (Some(nonce), Some(salt), Some(iterations)) if iterations > 0 =>
+            Some((nonce, salt, iterations)),
+        _ => None
+    }
+}
+
+fn sha256(data: &[u8]) -> ~[u8] {
+    let hasher = Hasher::new(SHA256);
+    hasher.update(data);
+    hasher.final()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> ~[u8] {
+    let mut hmac = HMAC(SHA256, key.to_owned());
+    hmac.update(data);
+    hmac.final()
+}
+
+// PBKDF2 with HMAC-SHA-256, producing a single block
+fn hi(password: &[u8], salt: &[u8], iterations: uint) -> ~[u8] {
+    let mut salt = salt.to_owned();
+    salt.push_all([0u8, 0, 0, 1]);
+
+    let mut prev = hmac(password, salt);
+    let mut hi = prev.clone();
+    for _ in range(1, iterations) {
+        prev = hmac(password, prev);
+        for (a, b) in hi.mut_iter().zip(prev.iter()) {
+            *a ^= *b;
+        }
+    }
+
+    hi
+}
@@ -0,0 +1,74 @@
+//! Connection service file lookup
+
+use std::os;
+
+use super::pgpass::read_lines;
+
+/// Looks up the parameters of a connection service.
+///
+/// The per-user service file, which is `file` if specified or else located by
+/// the `PGSERVICEFILE` environment variable and defaulting to
+/// `~/.pg_service.conf`, is checked first, followed by the system-wide
+/// `pg_service.conf` in the directory named by the `PGSYSCONFDIR` environment
+/// variable.
+///
+/// Returns `None` if the service is not defined in either file.
+pub fn find_service(name: &str, file: Option<~str>)
+        -> Option<~[(~str, ~str)]> {
+    let user_file = match file.or_else(|| os::getenv("PGSERVICEFILE")) {
+        Some(path) => Some(Path::new(path)),
+        None => os::homedir().map(|home| home.join(".pg_service.conf"))
+    };
+    let system_file = os::getenv("PGSYSCONFDIR").map(|dir| {
+        Path::new(dir).join("pg_service.conf")
+    });
+
+    for path in user_file.iter().chain(system_file.iter()) {
+        let lines = match read_lines(path) {
+            Some(lines) => lines,
+            None => continue
+        };
+
+        match parse_service(lines, name) {
+            Some(options) => return Some(options),
+            None => {}
+        }
+    }
+
+    None
+}
+
+// The file is made up of `[service]` sections containing `keyword=value` lines
+fn parse_service(lines: &[~str], name: &str) -> Option<~[(~str, ~str)]> {
+    let mut options = None;
+
+    for line in lines.iter() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+
+        if line.starts_with("[") && line.ends_with("]") {
+            if options.is_some() {
+                break;
+            }
+            if line.slice(1, line.len() - 1) == name {
+                options = Some(~[]);
+            }
+            continue;
+        }
+
+        match options {
+            Some(ref mut options) => match line.find('=') {
+                Some(idx) => options.push((line.slice_to(idx).trim().to_owned(),
+                                           line.slice_from(idx + 1).trim()
+                                                .to_owned())),
+                None => warn!("Ignoring invalid line in service {}: {}", name,
+                              line)
+            },
+            None => {}
+        }
+    }
+
+    options
+}
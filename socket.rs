@@ -0,0 +1,415 @@
+//! Sockets with connect and I/O timeouts and TCP keepalive settings
+//!
+//! The standard library's sockets don't expose any of these, so the socket is
+//! opened and configured through libc and then read and written directly.
+
+use std::cast;
+use std::cmp;
+use std::i32;
+use std::io;
+use std::io::{IoError, io_error};
+use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::libc::{c_int, c_long, c_void, size_t, ssize_t};
+use std::mem;
+use std::os;
+
+use super::PostgresKeepalives;
+
+type socklen_t = u32;
+
+#[cfg(target_os = "linux")]
+type nfds_t = ::std::libc::c_ulong;
+#[cfg(target_os = "macos")]
+type nfds_t = ::std::libc::c_uint;
+
+#[cfg(target_os = "linux")]
+type suseconds_t = c_long;
+#[cfg(target_os = "macos")]
+type suseconds_t = i32;
+
+struct pollfd {
+    fd: c_int,
+    events: i16,
+    revents: i16,
+}
+
+struct timeval {
+    tv_sec: c_long,
+    tv_usec: suseconds_t,
+}
+
+extern {
+    fn socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int;
+    fn connect(fd: c_int, addr: *u8, len: socklen_t) -> c_int;
+    fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *c_void,
+                  len: socklen_t) -> c_int;
+    fn getsockopt(fd: c_int, level: c_int, name: c_int, value: *mut c_void,
+                  len: *mut socklen_t) -> c_int;
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    fn poll(fds: *mut pollfd, nfds: nfds_t, timeout: c_int) -> c_int;
+    fn read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t;
+    fn write(fd: c_int, buf: *c_void, count: size_t) -> ssize_t;
+    fn close(fd: c_int) -> c_int;
+}
+
+static AF_UNIX: c_int = 1;
+static AF_INET: c_int = 2;
+static SOCK_STREAM: c_int = 1;
+static IPPROTO_TCP: c_int = 6;
+static F_GETFL: c_int = 3;
+static F_SETFL: c_int = 4;
+static POLLOUT: i16 = 4;
+static EINTR: int = 4;
+
+#[cfg(target_os = "linux")]
+mod consts {
+    use std::libc::c_int;
+
+    pub static AF_INET6: c_int = 10;
+    pub static SOL_SOCKET: c_int = 1;
+    pub static SO_ERROR: c_int = 4;
+    pub static SO_KEEPALIVE: c_int = 9;
+    pub static SO_RCVTIMEO: c_int = 20;
+    pub static SO_SNDTIMEO: c_int = 21;
+    pub static TCP_KEEPIDLE: c_int = 4;
+    pub static TCP_KEEPINTVL: c_int = 5;
+    pub static TCP_KEEPCNT: c_int = 6;
+    pub static O_NONBLOCK: c_int = 0o4000;
+    pub static SUN_PATH_LEN: uint = 108;
+
+    pub static EAGAIN: int = 11;
+    pub static EINPROGRESS: int = 115;
+    pub static ENOENT: int = 2;
+    pub static EACCES: int = 13;
+    pub static EPIPE: int = 32;
+    pub static ECONNABORTED: int = 103;
+    pub static ECONNRESET: int = 104;
+    pub static ENOTCONN: int = 107;
+    pub static ETIMEDOUT: int = 110;
+    pub static ECONNREFUSED: int = 111;
+
+    // sa_family_t is a native endian u16 at the start of the address
+    pub fn family_header(family: c_int, _len: uint) -> [u8, ..2] {
+        unsafe { ::std::cast::transmute(family as u16) }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod consts {
+    use std::libc::c_int;
+
+    pub static AF_INET6: c_int = 30;
+    pub static SOL_SOCKET: c_int = 0xffff;
+    pub static SO_ERROR: c_int = 0x1007;
+    pub static SO_KEEPALIVE: c_int = 0x8;
+    pub static SO_RCVTIMEO: c_int = 0x1006;
+    pub static SO_SNDTIMEO: c_int = 0x1005;
+    // Called TCP_KEEPALIVE on OSX
+    pub static TCP_KEEPIDLE: c_int = 0x10;
+    pub static TCP_KEEPINTVL: c_int = 0x101;
+    pub static TCP_KEEPCNT: c_int = 0x102;
+    pub static O_NONBLOCK: c_int = 0x4;
+    pub static SUN_PATH_LEN: uint = 104;
+
+    pub static EAGAIN: int = 35;
+    pub static EINPROGRESS: int = 36;
+    pub static ENOENT: int = 2;
+    pub static EACCES: int = 13;
+    pub static EPIPE: int = 32;
+    pub static ECONNABORTED: int = 53;
+    pub static ECONNRESET: int = 54;
+    pub static ENOTCONN: int = 57;
+    pub static ETIMEDOUT: int = 60;
+    pub static ECONNREFUSED: int = 61;
+
+    // BSD addresses start with their length followed by a u8 sa_family_t
+    pub fn family_header(family: c_int, len: uint) -> [u8, ..2] {
+        [len as u8, family as u8]
+    }
+}
+
+/// Returns true if the error was caused by a connect, read, or write timing
+/// out.
+pub fn is_timeout(err: &IoError) -> bool {
+    err.kind == io::ResourceUnavailable
+}
+
+fn timeout_error() -> IoError {
+    IoError {
+        kind: io::ResourceUnavailable,
+        desc: "operation timed out",
+        detail: None
+    }
+}
+
+fn errno_error(errno: int) -> IoError {
+    let (kind, desc) = match errno {
+        consts::EAGAIN | consts::ETIMEDOUT => return timeout_error(),
+        consts::ECONNREFUSED => (io::ConnectionRefused, "connection refused"),
+        consts::ECONNRESET => (io::ConnectionReset, "connection reset"),
+        consts::ECONNABORTED => (io::ConnectionAborted, "connection aborted"),
+        consts::ENOTCONN => (io::NotConnected, "not connected"),
+        consts::EPIPE => (io::BrokenPipe, "broken pipe"),
+        consts::ENOENT => (io::FileNotFound, "no such file or directory"),
+        consts::EACCES => (io::PermissionDenied, "permission denied"),
+        _ => (io::OtherIoError, "unknown error")
+    };
+
+    IoError {
+        kind: kind,
+        desc: desc,
+        detail: Some(os::last_os_error())
+    }
+}
+
+fn last_error() -> IoError {
+    errno_error(os::errno())
+}
+
+/// A connected TCP or Unix socket
+pub struct Socket {
+    priv fd: c_int,
+    priv eof: bool,
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { close(self.fd); }
+    }
+}
+
+impl Socket {
+    /// Opens a TCP connection to `addr`, waiting at most `timeout`
+    /// milliseconds if specified.
+    pub fn connect_tcp(addr: SocketAddr, timeout: Option<u64>)
+            -> Result<Socket, IoError> {
+        let mut buf = [0u8, ..28];
+        let port: [u8, ..2] = [(addr.port >> 8) as u8, addr.port as u8];
+        let (family, len) = match addr.ip {
+            Ipv4Addr(a, b, c, d) => {
+                // sin_port, sin_addr, then 8 bytes of padding
+                buf.mut_slice(2, 4).copy_from(port);
+                buf.mut_slice(4, 8).copy_from([a, b, c, d]);
+                (AF_INET, 16)
+            }
+            Ipv6Addr(a, b, c, d, e, f, g, h) => {
+                // sin6_port, sin6_flowinfo, sin6_addr, then sin6_scope_id
+                buf.mut_slice(2, 4).copy_from(port);
+                for (i, word) in [a, b, c, d, e, f, g, h].iter().enumerate() {
+                    buf[8 + 2 * i] = (*word >> 8) as u8;
+                    buf[9 + 2 * i] = *word as u8;
+                }
+                (consts::AF_INET6, 28)
+            }
+        };
+        buf.mut_slice(0, 2).copy_from(consts::family_header(family, len));
+
+        Socket::connect(family, buf.slice_to(len), timeout)
+    }
+
+    /// Opens a connection to the Unix socket at `path`, waiting at most
+    /// `timeout` milliseconds if specified.
+    pub fn connect_unix(path: &Path, timeout: Option<u64>)
+            -> Result<Socket, IoError> {
+        let path = path.as_vec();
+        // The path must be null terminated
+        if path.len() >= consts::SUN_PATH_LEN || path.contains(&0) {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "invalid socket path",
+                detail: None
+            });
+        }
+
+        let len = 2 + path.len() + 1;
+        let mut buf = [0u8, ..2 + consts::SUN_PATH_LEN];
+        buf.mut_slice(0, 2).copy_from(consts::family_header(AF_UNIX, len));
+        buf.mut_slice(2, 2 + path.len()).copy_from(path);
+
+        Socket::connect(AF_UNIX, buf.slice_to(len), timeout)
+    }
+
+    fn connect(family: c_int, addr: &[u8], timeout: Option<u64>)
+            -> Result<Socket, IoError> {
+        let fd = unsafe { socket(family, SOCK_STREAM, 0) };
+        if fd < 0 {
+            return Err(last_error());
+        }
+        // Closes the descriptor if anything below fails
+        let socket = Socket { fd: fd, eof: false };
+
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                if unsafe { connect(fd, addr.as_ptr(),
+                                    addr.len() as socklen_t) } < 0 {
+                    return Err(last_error());
+                }
+                return Ok(socket);
+            }
+        };
+
+        // The connection is started without blocking and then polled until
+        // it completes or the timeout expires
+        match socket.set_nonblocking(true) {
+            Ok(()) => {}
+            Err(err) => return Err(err)
+        }
+        if unsafe { connect(fd, addr.as_ptr(), addr.len() as socklen_t) } < 0 {
+            if os::errno() != consts::EINPROGRESS {
+                return Err(last_error());
+            }
+
+            let mut pfd = pollfd { fd: fd, events: POLLOUT, revents: 0 };
+            let timeout = cmp::min(timeout, i32::max_value as u64) as c_int;
+            loop {
+                match unsafe { poll(&mut pfd, 1, timeout) } {
+                    0 => return Err(timeout_error()),
+                    n if n < 0 && os::errno() == EINTR => {}
+                    n if n < 0 => return Err(last_error()),
+                    _ => break
+                }
+            }
+
+            let mut err: c_int = 0;
+            let mut len = mem::size_of::<c_int>() as socklen_t;
+            if unsafe { getsockopt(fd, consts::SOL_SOCKET, consts::SO_ERROR,
+                                   cast::transmute(&mut err), &mut len) } < 0 {
+                return Err(last_error());
+            }
+            if err != 0 {
+                return Err(errno_error(err as int));
+            }
+        }
+        match socket.set_nonblocking(false) {
+            Ok(()) => {}
+            Err(err) => return Err(err)
+        }
+
+        Ok(socket)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(), IoError> {
+        let flags = unsafe { fcntl(self.fd, F_GETFL) };
+        if flags < 0 {
+            return Err(last_error());
+        }
+        let flags = if nonblocking {
+            flags | consts::O_NONBLOCK
+        } else {
+            flags & !consts::O_NONBLOCK
+        };
+        if unsafe { fcntl(self.fd, F_SETFL, flags) } < 0 {
+            return Err(last_error());
+        }
+        Ok(())
+    }
+
+    fn set_option<T>(&self, level: c_int, name: c_int, value: T)
+            -> Result<(), IoError> {
+        let len = mem::size_of::<T>() as socklen_t;
+        if unsafe { setsockopt(self.fd, level, name, cast::transmute(&value),
+                               len) } < 0 {
+            return Err(last_error());
+        }
+        Ok(())
+    }
+
+    /// Enables TCP keepalives with the specified settings, or disables them
+    /// if `None`.
+    ///
+    /// The interval and count are left at the system defaults if not
+    /// specified.
+    pub fn set_keepalives(&self, keepalives: &Option<PostgresKeepalives>)
+            -> Result<(), IoError> {
+        let keepalives = match *keepalives {
+            Some(ref keepalives) => keepalives,
+            None =>
+                return self.set_option(consts::SOL_SOCKET,
+                                       consts::SO_KEEPALIVE, 0 as c_int)
+        };
+
+        let mut options = ~[(consts::SOL_SOCKET, consts::SO_KEEPALIVE, 1),
+                            (IPPROTO_TCP, consts::TCP_KEEPIDLE,
+                             keepalives.idle)];
+        match keepalives.interval {
+            Some(interval) =>
+                options.push((IPPROTO_TCP, consts::TCP_KEEPINTVL, interval)),
+            None => {}
+        }
+        match keepalives.count {
+            Some(count) =>
+                options.push((IPPROTO_TCP, consts::TCP_KEEPCNT, count)),
+            None => {}
+        }
+
+        for &(level, name, value) in options.iter() {
+            match self.set_option(level, name, value as c_int) {
+                Ok(()) => {}
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    /// Limits reads and writes to `timeout` milliseconds, or lets them block
+    /// indefinitely if `None`.
+    pub fn set_timeout(&self, timeout: Option<u64>) -> Result<(), IoError> {
+        // A zero timeval blocks forever
+        let timeout = timeout.unwrap_or(0);
+        let tv = timeval {
+            tv_sec: (timeout / 1000) as c_long,
+            tv_usec: ((timeout % 1000) * 1000) as suseconds_t
+        };
+        match self.set_option(consts::SOL_SOCKET, consts::SO_RCVTIMEO, tv) {
+            Ok(()) => {}
+            Err(err) => return Err(err)
+        }
+        self.set_option(consts::SOL_SOCKET, consts::SO_SNDTIMEO, tv)
+    }
+}
+
+impl Reader for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        loop {
+            let n = unsafe {
+                read(self.fd, buf.as_mut_ptr() as *mut c_void,
+                     buf.len() as size_t)
+            };
+            if n > 0 {
+                return Some(n as uint);
+            } else if n == 0 {
+                self.eof = true;
+                io_error::cond.raise(io::standard_error(io::EndOfFile));
+                return None;
+            } else if os::errno() != EINTR {
+                io_error::cond.raise(last_error());
+                return None;
+            }
+        }
+    }
+
+    fn eof(&mut self) -> bool {
+        self.eof
+    }
+}
+
+impl Writer for Socket {
+    fn write(&mut self, buf: &[u8]) {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let rest = buf.slice_from(pos);
+            let n = unsafe {
+                write(self.fd, rest.as_ptr() as *c_void, rest.len() as size_t)
+            };
+            if n >= 0 {
+                pos += n as uint;
+            } else if os::errno() != EINTR {
+                io_error::cond.raise(last_error());
+                return;
+            }
+        }
+    }
+
+    fn flush(&mut self) {}
+}
@@ -0,0 +1,268 @@
+//! SSL context setup and certificate verification
+
+use openssl::crypto::hash::{HashType, SHA224, SHA256, SHA384, SHA512};
+use openssl::nid;
+use openssl::ssl::{SslContext, SslVerifyPeer, Sslv23};
+use openssl::x509::{X509, PEM};
+use std::ascii::StrAsciiExt;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os;
+
+use super::error::{PostgresConnectError, InvalidParam, SslError};
+
+/// The ALPN protocol identifying Postgres connections
+pub static ALPN_PROTOCOL: &'static str = "postgresql";
+
+/// Files to load into an SSL context
+pub struct SslFiles {
+    /// Certificate authorities trusted to sign the server's certificate
+    rootcert: Option<~str>,
+    /// The client's certificate
+    cert: Option<~str>,
+    /// The private key for the client's certificate
+    key: Option<~str>,
+    /// Revoked server certificates
+    crl: Option<~str>,
+}
+
+impl SslFiles {
+    /// Returns true if any of the files were specified.
+    pub fn any(&self) -> bool {
+        self.rootcert.is_some() || self.cert.is_some() || self.key.is_some()
+            || self.crl.is_some()
+    }
+}
+
+/// Creates an SSL context, loading the specified files into it.
+///
+/// If `verify` is set, the server's certificate will be verified against the
+/// trusted certificate authorities, which default to
+/// `~/.postgresql/root.crt`. Like libpq, the client certificate, key and
+/// revocation list default to `postgresql.crt`, `postgresql.key`, and
+/// `root.crl` in the same directory if those files exist.
+pub fn make_context(verify: bool, files: SslFiles)
+        -> Result<SslContext, PostgresConnectError> {
+    let SslFiles { rootcert, cert, key, crl } = files;
+    let mut ctx = SslContext::new(Sslv23);
+    // Required for direct SSL connections, and harmless otherwise
+    ctx.set_alpn_protocols([ALPN_PROTOCOL.as_bytes()]);
+
+    if verify {
+        let rootcert = match rootcert.or_else(|| default_file("root.crt")) {
+            Some(rootcert) => rootcert,
+            None => return Err(InvalidParam(~"sslrootcert"))
+        };
+        ctx.set_verify(SslVerifyPeer, None);
+        match ctx.set_CA_file(rootcert) {
+            Some(err) => return Err(SslError(err)),
+            None => {}
+        }
+        match crl.or_else(|| default_file("root.crl")) {
+            Some(crl) => match ctx.set_CRL_file(crl) {
+                Some(err) => return Err(SslError(err)),
+                None => {}
+            },
+            None => {}
+        }
+    }
+
+    match cert.or_else(|| default_file("postgresql.crt")) {
+        Some(cert) => match ctx.set_certificate_file(cert, PEM) {
+            Some(err) => return Err(SslError(err)),
+            None => {}
+        },
+        None => {}
+    }
+    match key.or_else(|| default_file("postgresql.key")) {
+        Some(key) => match ctx.set_private_key_file(key, PEM) {
+            Some(err) => return Err(SslError(err)),
+            None => {}
+        },
+        None => {}
+    }
+
+    Ok(ctx)
+}
+
+/// Returns the hash algorithm used to compute the `tls-server-end-point`
+/// channel binding data for the certificate.
+///
+/// As specified by RFC 5929, it's the hash algorithm of the certificate's
+/// signature, except that MD5 and SHA-1 are replaced by SHA-256. Returns
+/// `None` if the signature algorithm doesn't identify a single hash, as is
+/// the case for RSASSA-PSS and Ed25519.
+pub fn end_point_hash(cert: &X509) -> Option<HashType> {
+    match cert.signature_algorithm() {
+        nid::MD5WithRSAEncryption | nid::SHA1WithRSAEncryption
+            | nid::DSAWithSHA1 | nid::ECDSAWithSHA1
+            | nid::SHA256WithRSAEncryption | nid::DSAWithSHA256
+            | nid::ECDSAWithSHA256 => Some(SHA256),
+        nid::SHA224WithRSAEncryption | nid::DSAWithSHA224
+            | nid::ECDSAWithSHA224 => Some(SHA224),
+        nid::SHA384WithRSAEncryption | nid::ECDSAWithSHA384 => Some(SHA384),
+        nid::SHA512WithRSAEncryption | nid::ECDSAWithSHA512 => Some(SHA512),
+        _ => None
+    }
+}
+
+fn default_file(name: &str) -> Option<~str> {
+    os::homedir().map(|home| home.join_many([".postgresql", name]))
+            .filtered(|path| path.exists())
+            .and_then(|path| path.as_str().map(|s| s.to_owned()))
+}
+
+/// Checks that the certificate was issued for the specified host.
+///
+/// The subject alternative name extension is checked if it is present, and
+/// the common name otherwise, as described by RFC 6125. Like libpq, a host
+/// which is an IP address only matches iPAddress alternative names, and any
+/// other host only matches dNSName alternative names.
+pub fn verify_hostname(cert: &X509, host: &str) -> bool {
+    let alt_names = cert.subject_alt_names().map(|names| {
+        names.iter().filter_map(|name| {
+            match (name.dnsname(), name.ipaddress()) {
+                (Some(dns), _) => Some(DnsName(dns.to_owned())),
+                (None, Some(ip)) => ip_from_bytes(ip).map(IpAddressName),
+                (None, None) => None
+            }
+        }).collect()
+    });
+    let common_name = cert.subject_name().text_by_nid(nid::CN);
+    names_match(alt_names, common_name, host)
+}
+
+/// A subject alternative name of a certificate
+enum AltName {
+    DnsName(~str),
+    IpAddressName(IpAddr)
+}
+
+// The common name is only used if the certificate has no subject alternative
+// names at all
+fn names_match(alt_names: Option<~[AltName]>, common_name: Option<~str>,
+               host: &str) -> bool {
+    let ip: Option<IpAddr> = FromStr::from_str(host);
+
+    match alt_names {
+        Some(names) => names.iter().any(|name| {
+            match (name, ip) {
+                (&DnsName(ref pattern), None) =>
+                    matches(pattern.as_slice(), host),
+                (&IpAddressName(addr), Some(ip)) => addr == ip,
+                _ => false
+            }
+        }),
+        None => match (common_name, ip) {
+            (Some(name), None) => matches(name.as_slice(), host),
+            // Wildcards never match IP addresses
+            (Some(name), Some(ip)) => {
+                let name: Option<IpAddr> = FromStr::from_str(name.as_slice());
+                name == Some(ip)
+            }
+            (None, _) => false
+        }
+    }
+}
+
+// Addresses are stored in network byte order
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(Ipv4Addr(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => {
+            let mut words = [0u16, ..8];
+            for (i, word) in words.mut_iter().enumerate() {
+                *word = (bytes[2 * i] as u16 << 8) | bytes[2 * i + 1] as u16;
+            }
+            Some(Ipv6Addr(words[0], words[1], words[2], words[3], words[4],
+                          words[5], words[6], words[7]))
+        }
+        _ => None
+    }
+}
+
+// A `*` is only allowed as the entire leftmost label of the pattern, and
+// matches exactly one label of the host.
+fn matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lower();
+    let host = host.to_ascii_lower();
+
+    if !pattern.starts_with("*.") {
+        return pattern == host;
+    }
+
+    match host.find('.') {
+        Some(idx) => idx > 0 && host.slice_from(idx) == pattern.slice_from(1),
+        None => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::net::ip::{Ipv4Addr, Ipv6Addr};
+
+    use super::{names_match, matches, ip_from_bytes, DnsName, IpAddressName};
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("db.example.com", "db.example.com"));
+        assert!(matches("DB.Example.com", "db.example.COM"));
+        assert!(!matches("db.example.com", "other.example.com"));
+    }
+
+    #[test]
+    fn test_matches_wildcard() {
+        assert!(matches("*.example.com", "db.example.com"));
+        // A wildcard matches exactly one label
+        assert!(!matches("*.example.com", "a.db.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("*.example.com", ".example.com"));
+        // Only an entire leftmost label can be a wildcard
+        assert!(!matches("db*.example.com", "db1.example.com"));
+        assert!(!matches("db.*.com", "db.example.com"));
+    }
+
+    #[test]
+    fn test_dns_host() {
+        let names = ~[IpAddressName(Ipv4Addr(127, 0, 0, 1)),
+                      DnsName(~"*.example.com")];
+        assert!(names_match(Some(names), None, "db.example.com"));
+        let names = ~[DnsName(~"other.example.com")];
+        assert!(!names_match(Some(names), None, "db.example.com"));
+    }
+
+    #[test]
+    fn test_ip_host() {
+        let names = ~[DnsName(~"127.0.0.1"), DnsName(~"*.0.0.1")];
+        assert!(!names_match(Some(names), None, "127.0.0.1"));
+        let names = ~[DnsName(~"localhost"),
+                      IpAddressName(Ipv4Addr(127, 0, 0, 1))];
+        assert!(names_match(Some(names), None, "127.0.0.1"));
+        let names = ~[IpAddressName(Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1))];
+        assert!(names_match(Some(names), None, "::1"));
+        let names = ~[IpAddressName(Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1))];
+        assert!(!names_match(Some(names), None, "127.0.0.1"));
+    }
+
+    #[test]
+    fn test_common_name_fallback() {
+        assert!(names_match(None, Some(~"db.example.com"), "db.example.com"));
+        assert!(names_match(None, Some(~"127.0.0.1"), "127.0.0.1"));
+        assert!(!names_match(None, Some(~"*.0.0.1"), "127.0.0.1"));
+        assert!(!names_match(None, None, "db.example.com"));
+        // The common name is ignored if there are any alternative names
+        let names = ~[DnsName(~"other.example.com")];
+        assert!(!names_match(Some(names), Some(~"db.example.com"),
+                             "db.example.com"));
+        assert!(!names_match(Some(~[]), Some(~"db.example.com"),
+                             "db.example.com"));
+    }
+
+    #[test]
+    fn test_ip_from_bytes() {
+        assert_eq!(Some(Ipv4Addr(10, 0, 0, 1)), ip_from_bytes([10, 0, 0, 1]));
+        assert_eq!(Some(Ipv6Addr(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                   ip_from_bytes([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0,
+                                  0, 0, 0, 0, 0, 0, 0, 1]));
+        assert_eq!(None, ip_from_bytes([127, 0, 0]));
+    }
+}
@@ -8,5 +8,25 @@ psql -U postgres < setup.sql
 sudo cp pg_hba.conf $(psql -U postgres -c "SHOW hba_file" -At)
 
 DATA_DIR=$(psql -U postgres -c "SHOW data_directory" -At)
+
+# A certificate authority and a server certificate signed by it for the SSL
+# verification tests
+SSL_DIR=/tmp/rust-postgres-ssl
+mkdir -p $SSL_DIR
+openssl req -new -x509 -nodes -days 365 -subj "/CN=rust-postgres test CA" \
+    -keyout $SSL_DIR/root.key -out $SSL_DIR/root.crt
+openssl req -new -nodes -subj "/CN=localhost" \
+    -keyout $SSL_DIR/server.key -out $SSL_DIR/server.csr
+echo "subjectAltName=DNS:localhost,IP:127.0.0.1" > $SSL_DIR/server.ext
+openssl x509 -req -days 365 -in $SSL_DIR/server.csr -CA $SSL_DIR/root.crt \
+    -CAkey $SSL_DIR/root.key -CAcreateserial -extfile $SSL_DIR/server.ext \
+    -out $SSL_DIR/server.crt
+sudo cp $SSL_DIR/server.crt $SSL_DIR/server.key $DATA_DIR
+sudo chown postgres:postgres $DATA_DIR/server.crt $DATA_DIR/server.key
+sudo chmod 600 $DATA_DIR/server.key
+psql -U postgres -c "ALTER SYSTEM SET ssl = on"
+psql -U postgres -c "ALTER SYSTEM SET ssl_cert_file = 'server.crt'"
+psql -U postgres -c "ALTER SYSTEM SET ssl_key_file = 'server.key'"
+
 PG_PID=$(sudo head -n1 $DATA_DIR/postmaster.pid)
 sudo kill -SIGHUP $PG_PID
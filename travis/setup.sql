@@ -1,3 +1,5 @@
 CREATE ROLE pass_user PASSWORD 'password' LOGIN;
 CREATE ROLE md5_user PASSWORD 'password' LOGIN;
+SET password_encryption = 'scram-sha-256';
+CREATE ROLE scram_user PASSWORD 'password' LOGIN;
 CREATE EXTENSION hstore;
@@ -8,12 +8,13 @@ use extra::json;
 use extra::json::Json;
 use extra::uuid::Uuid;
 use std::hashmap::HashMap;
-use std::io::Decorator;
+use std::io::{Decorator, io_error};
 use std::io::mem::{MemWriter, BufReader};
 use std::io::util::LimitReader;
 use std::str;
 use std::vec;
 
+use super::error::{PostgresError, PgWrongType, PgWasNull, PgBadResponse};
 use self::array::{Array, ArrayBase, DimensionInfo};
 use self::range::{RangeBound, Inclusive, Exclusive, Range};
 
@@ -78,7 +79,7 @@ static RANGE_EMPTY: i8           = 0b0000_0001;
 macro_rules! make_postgres_type(
     ($($doc:attr $oid:ident => $variant:ident $(member $member:ident)*),+) => (
         /// A Postgres type
-        #[deriving(Eq)]
+        #[deriving(Eq, Clone, ToStr)]
         pub enum PostgresType {
             $(
                 $doc
@@ -223,7 +224,7 @@ macro_rules! check_types(
     ($($expected:pat)|+, $actual:ident) => (
         match $actual {
             $(&$expected)|+ => (),
-            actual => fail!("Invalid Postgres type {:?}", actual)
+            actual => return Err(PgWrongType(actual.clone()))
         }
     )
 )
@@ -234,10 +235,11 @@ pub trait FromSql {
     ///
     /// If the value was `NULL`, the buffer will be `None`.
     ///
-    /// # Failure
-    ///
-    /// Fails if this type can not be created from the provided Postgres type.
-    fn from_sql(ty: &PostgresType, raw: &Option<~[u8]>) -> Self;
+    /// Returns `PgWrongType` if this type can not be created from the
+    /// provided Postgres type, and `PgWasNull` if the value was `NULL` but
+    /// this type can not represent it.
+    fn from_sql(ty: &PostgresType, raw: &Option<~[u8]>)
+            -> Result<Self, PostgresError>;
 }
 
 trait RawFromSql {
@@ -355,17 +357,34 @@ impl RawFromSql for Json {
 macro_rules! from_map_impl(
     ($($expected:pat)|+, $t:ty, $blk:expr) => (
         impl FromSql for Option<$t> {
-            fn from_sql(ty: &PostgresType, raw: &Option<~[u8]>) -> Option<$t> {
+            fn from_sql(ty: &PostgresType, raw: &Option<~[u8]>)
+                    -> Result<Option<$t>, PostgresError> {
                 check_types!($($expected)|+, ty)
-                raw.as_ref().map($blk)
+                // A value that's too short for its type runs off the end of
+                // the reader
+                let mut truncated = false;
+                let ret = io_error::cond.trap(|_| truncated = true).inside(|| {
+                    raw.as_ref().map($blk)
+                });
+                if truncated {
+                    Err(PgBadResponse)
+                } else {
+                    Ok(ret)
+                }
             }
         }
 
         impl FromSql for $t {
-            fn from_sql(ty: &PostgresType, raw: &Option<~[u8]>) -> $t {
+            fn from_sql(ty: &PostgresType, raw: &Option<~[u8]>)
+                    -> Result<$t, PostgresError> {
                 // FIXME when you can specify Self types properly
-                let ret: Option<$t> = FromSql::from_sql(ty, raw);
-                ret.unwrap()
+                let ret: Result<Option<$t>, PostgresError> =
+                    FromSql::from_sql(ty, raw);
+                match ret {
+                    Ok(Some(val)) => Ok(val),
+                    Ok(None) => Err(PgWasNull),
+                    Err(err) => Err(err)
+                }
             }
         }
     )
@@ -476,11 +495,21 @@ pub trait ToSql {
     /// Converts the value of `self` into a format appropriate for the Postgres
     /// backend.
     ///
-    /// # Failure
+    /// Returns `PgWrongType` if this type cannot be converted into the
+    /// specified Postgres type.
+    fn to_sql(&self, ty: &PostgresType)
+            -> Result<(Format, Option<~[u8]>), PostgresError>;
+
+    /// Returns the Postgres type that values of this type are converted into
+    /// by default, if there is one.
     ///
-    /// Fails if this type cannot be converted into the specified Postgres
-    /// type.
-    fn to_sql(&self, ty: &PostgresType) -> (Format, Option<~[u8]>);
+    /// It is used by `PostgresConnection::execute_unprepared` to specify the
+    /// types of the parameters of queries which are executed without being
+    /// prepared separately. Types which could be converted into more than one
+    /// Postgres type with different meanings have no preferred type.
+    fn preferred_type(&self) -> Option<PostgresType> {
+        None
+    }
 }
 
 trait RawToSql {
@@ -596,90 +625,119 @@ impl RawToSql for Json {
 }
 
 macro_rules! to_option_impl(
-    ($($oid:pat)|+, $t:ty) => (
+    ($preferred:expr, $($oid:pat)|+, $t:ty) => (
         impl ToSql for Option<$t> {
-            fn to_sql(&self, ty: &PostgresType) -> (Format, Option<~[u8]>) {
+            fn to_sql(&self, ty: &PostgresType)
+            -> Result<(Format, Option<~[u8]>), PostgresError> {
                 check_types!($($oid)|+, ty)
 
                 match *self {
-                    None => (Text, None),
+                    None => Ok((Text, None)),
                     Some(ref val) => val.to_sql(ty)
                 }
             }
+
+            fn preferred_type(&self) -> Option<PostgresType> {
+                $preferred
+            }
         }
     )
 )
 
 macro_rules! to_option_impl_lifetime(
-    ($($oid:pat)|+, $t:ty) => (
+    ($preferred:expr, $($oid:pat)|+, $t:ty) => (
         impl<'a> ToSql for Option<$t> {
-            fn to_sql(&self, ty: &PostgresType) -> (Format, Option<~[u8]>) {
+            fn to_sql(&self, ty: &PostgresType)
+            -> Result<(Format, Option<~[u8]>), PostgresError> {
                 check_types!($($oid)|+, ty)
 
                 match *self {
-                    None => (Text, None),
+                    None => Ok((Text, None)),
                     Some(ref val) => val.to_sql(ty)
                 }
             }
+
+            fn preferred_type(&self) -> Option<PostgresType> {
+                $preferred
+            }
         }
     )
 )
 
 macro_rules! to_raw_to_impl(
-    ($($oid:ident)|+, $t:ty) => (
+    ($preferred:expr, $($oid:ident)|+, $t:ty) => (
         impl ToSql for $t {
-            fn to_sql(&self, ty: &PostgresType) -> (Format, Option<~[u8]>) {
+            fn to_sql(&self, ty: &PostgresType)
+            -> Result<(Format, Option<~[u8]>), PostgresError> {
                 check_types!($($oid)|+, ty)
 
                 let mut writer = MemWriter::new();
                 self.raw_to_sql(&mut writer);
-                (Binary, Some(writer.inner()))
+                Ok((Binary, Some(writer.inner())))
+            }
+
+            fn preferred_type(&self) -> Option<PostgresType> {
+                $preferred
             }
         }
 
-        to_option_impl!($($oid)|+, $t)
+        to_option_impl!($preferred, $($oid)|+, $t)
     )
 )
 
-to_raw_to_impl!(PgBool, bool)
-to_raw_to_impl!(PgByteA, ~[u8])
-to_raw_to_impl!(PgVarchar | PgText | PgCharN, ~str)
-to_raw_to_impl!(PgJson, Json)
-to_raw_to_impl!(PgChar, i8)
-to_raw_to_impl!(PgInt2, i16)
-to_raw_to_impl!(PgInt4, i32)
-to_raw_to_impl!(PgInt8, i64)
-to_raw_to_impl!(PgFloat4, f32)
-to_raw_to_impl!(PgFloat8, f64)
-to_raw_to_impl!(PgInt4Range, Range<i32>)
-to_raw_to_impl!(PgInt8Range, Range<i64>)
-to_raw_to_impl!(PgTsRange | PgTstzRange, Range<Timespec>)
+to_raw_to_impl!(Some(PgBool), PgBool, bool)
+to_raw_to_impl!(Some(PgByteA), PgByteA, ~[u8])
+to_raw_to_impl!(Some(PgVarchar), PgVarchar | PgText | PgCharN, ~str)
+to_raw_to_impl!(Some(PgJson), PgJson, Json)
+to_raw_to_impl!(Some(PgChar), PgChar, i8)
+to_raw_to_impl!(Some(PgInt2), PgInt2, i16)
+to_raw_to_impl!(Some(PgInt4), PgInt4, i32)
+to_raw_to_impl!(Some(PgInt8), PgInt8, i64)
+to_raw_to_impl!(Some(PgFloat4), PgFloat4, f32)
+to_raw_to_impl!(Some(PgFloat8), PgFloat8, f64)
+to_raw_to_impl!(Some(PgInt4Range), PgInt4Range, Range<i32>)
+to_raw_to_impl!(Some(PgInt8Range), PgInt8Range, Range<i64>)
+to_raw_to_impl!(None, PgTsRange | PgTstzRange, Range<Timespec>)
 
 impl<'a> ToSql for &'a str {
-    fn to_sql(&self, ty: &PostgresType) -> (Format, Option<~[u8]>) {
+    fn to_sql(&self, ty: &PostgresType)
+            -> Result<(Format, Option<~[u8]>), PostgresError> {
         check_types!(PgVarchar | PgText | PgCharN, ty)
-        (Text, Some(self.as_bytes().to_owned()))
+        Ok((Text, Some(self.as_bytes().to_owned())))
+    }
+
+    fn preferred_type(&self) -> Option<PostgresType> {
+        Some(PgVarchar)
     }
 }
 
-to_option_impl_lifetime!(PgVarchar | PgText | PgCharN, &'a str)
+to_option_impl_lifetime!(Some(PgVarchar), PgVarchar | PgText | PgCharN,
+                         &'a str)
 
 impl<'a> ToSql for &'a [u8] {
-    fn to_sql(&self, ty: &PostgresType) -> (Format, Option<~[u8]>) {
+    fn to_sql(&self, ty: &PostgresType)
+            -> Result<(Format, Option<~[u8]>), PostgresError> {
         check_types!(PgByteA, ty)
-        (Binary, Some(self.to_owned()))
+        Ok((Binary, Some(self.to_owned())))
+    }
+
+    fn preferred_type(&self) -> Option<PostgresType> {
+        Some(PgByteA)
     }
 }
 
-to_option_impl_lifetime!(PgByteA, &'a [u8])
+to_option_impl_lifetime!(Some(PgByteA), PgByteA, &'a [u8])
 
-to_raw_to_impl!(PgTimestamp | PgTimestampTZ, Timespec)
-to_raw_to_impl!(PgUuid, Uuid)
+// The server interprets a Timespec differently depending on whether it's a
+// TIMESTAMP or a TIMESTAMPTZ, so there's no safe default
+to_raw_to_impl!(None, PgTimestamp | PgTimestampTZ, Timespec)
+to_raw_to_impl!(Some(PgUuid), PgUuid, Uuid)
 
 macro_rules! to_array_impl(
-    ($($oid:ident)|+, $t:ty) => (
+    ($preferred:expr, $($oid:ident)|+, $t:ty) => (
         impl ToSql for ArrayBase<Option<$t>> {
-            fn to_sql(&self, ty: &PostgresType) -> (Format, Option<~[u8]>) {
+            fn to_sql(&self, ty: &PostgresType)
+            -> Result<(Format, Option<~[u8]>), PostgresError> {
                 check_types!($($oid)|+, ty)
                 let mut buf = MemWriter::new();
 
@@ -705,32 +763,38 @@ macro_rules! to_array_impl(
                     }
                 }
 
-                (Binary, Some(buf.inner()))
+                Ok((Binary, Some(buf.inner())))
+            }
+
+            fn preferred_type(&self) -> Option<PostgresType> {
+                $preferred
             }
         }
 
-        to_option_impl!($($oid)|+, ArrayBase<Option<$t>>)
+        to_option_impl!($preferred, $($oid)|+, ArrayBase<Option<$t>>)
     )
 )
 
-to_array_impl!(PgBoolArray, bool)
-to_array_impl!(PgByteAArray, ~[u8])
-to_array_impl!(PgCharArray, i8)
-to_array_impl!(PgInt2Array, i16)
-to_array_impl!(PgInt4Array, i32)
-to_array_impl!(PgTextArray | PgCharNArray | PgVarcharArray, ~str)
-to_array_impl!(PgInt8Array, i64)
-to_array_impl!(PgTimestampArray | PgTimestampTZArray, Timespec)
-to_array_impl!(PgFloat4Array, f32)
-to_array_impl!(PgFloat8Array, f64)
-to_array_impl!(PgUuidArray, Uuid)
-to_array_impl!(PgInt4RangeArray, Range<i32>)
-to_array_impl!(PgTsRangeArray | PgTstzRangeArray, Range<Timespec>)
-to_array_impl!(PgInt8RangeArray, Range<i64>)
-to_array_impl!(PgJsonArray, Json)
+to_array_impl!(Some(PgBoolArray), PgBoolArray, bool)
+to_array_impl!(Some(PgByteAArray), PgByteAArray, ~[u8])
+to_array_impl!(Some(PgCharArray), PgCharArray, i8)
+to_array_impl!(Some(PgInt2Array), PgInt2Array, i16)
+to_array_impl!(Some(PgInt4Array), PgInt4Array, i32)
+to_array_impl!(Some(PgTextArray), PgTextArray | PgCharNArray | PgVarcharArray,
+               ~str)
+to_array_impl!(Some(PgInt8Array), PgInt8Array, i64)
+to_array_impl!(None, PgTimestampArray | PgTimestampTZArray, Timespec)
+to_array_impl!(Some(PgFloat4Array), PgFloat4Array, f32)
+to_array_impl!(Some(PgFloat8Array), PgFloat8Array, f64)
+to_array_impl!(Some(PgUuidArray), PgUuidArray, Uuid)
+to_array_impl!(Some(PgInt4RangeArray), PgInt4RangeArray, Range<i32>)
+to_array_impl!(None, PgTsRangeArray | PgTstzRangeArray, Range<Timespec>)
+to_array_impl!(Some(PgInt8RangeArray), PgInt8RangeArray, Range<i64>)
+to_array_impl!(Some(PgJsonArray), PgJsonArray, Json)
 
 impl<'a> ToSql for HashMap<~str, Option<~str>> {
-    fn to_sql(&self, ty: &PostgresType) -> (Format, Option<~[u8]>) {
+    fn to_sql(&self, ty: &PostgresType)
+            -> Result<(Format, Option<~[u8]>), PostgresError> {
         check_types!(PgUnknownType { name: ~"hstore", .. }, ty)
         let mut buf = MemWriter::new();
 
@@ -749,8 +813,9 @@ impl<'a> ToSql for HashMap<~str, Option<~str>> {
             }
         }
 
-        (Binary, Some(buf.inner()))
+        Ok((Binary, Some(buf.inner())))
     }
 }
-to_option_impl!(PgUnknownType { name: ~"hstore", .. },
+// The OID of HSTORE isn't fixed, so there's no preferred type
+to_option_impl!(None, PgUnknownType { name: ~"hstore", .. },
                 HashMap<~str, Option<~str>>)